@@ -0,0 +1,302 @@
+use std::{io::Result, path::Path};
+
+use proc_macro2::TokenStream;
+use prost_build::Service;
+use quote::{format_ident, quote};
+
+/// Compile `protos` with prost and generate rspc stubs for their services,
+/// call it from `build.rs` in place of `prost_build::compile_protos`.
+pub fn compile_protos(protos: &[impl AsRef<Path>], includes: &[impl AsRef<Path>]) -> Result<()> {
+    configure().compile_protos(protos, includes)
+}
+
+/// A `prost_build::Config` with `ServiceGenerator` installed.
+pub fn configure() -> prost_build::Config {
+    let mut config = prost_build::Config::new();
+    config.service_generator(Box::new(ServiceGenerator::new()));
+    config
+}
+
+/// Generates for every proto `service Foo`:
+///
+/// - `trait Foo`, implemented by the application,
+/// - `FooServer<T: Foo>`, an `rspc::server::Service` dispatching to it,
+/// - `FooClient<'a>`, a typed client stub.
+///
+/// Method ids follow the `rpc` order in the proto file. Messages are encoded
+/// with `ProstCodec`, unary methods with an `idempotency_level` option may
+/// be retried. A `stream` request is a `RequestStream` on the server
+/// and a `futures::Stream` on the client, a `stream` reply a `ReplySink`
+/// on the server and a `ReplyStream` on the client.
+#[derive(Debug, Default)]
+pub struct ServiceGenerator {}
+
+impl ServiceGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl prost_build::ServiceGenerator for ServiceGenerator {
+    fn generate(&mut self, service: Service, buf: &mut String) {
+        let tokens = generate_service(&service);
+        buf.push_str(&tokens.to_string());
+        buf.push('\n');
+    }
+}
+
+fn rust_type(path: &str) -> syn::Type {
+    syn::parse_str(path).expect("prost generated an invalid type path")
+}
+
+fn doc(comments: &prost_build::Comments) -> TokenStream {
+    let lines = comments.leading.iter().flat_map(|c| c.lines());
+    quote! { #(#[doc = #lines])* }
+}
+
+/// `option idempotency_level` is `NO_SIDE_EFFECTS` or `IDEMPOTENT`.
+fn is_idempotent(method: &prost_build::Method) -> bool {
+    matches!(method.options.idempotency_level, Some(1) | Some(2))
+}
+
+fn generate_service(service: &Service) -> TokenStream {
+    let trait_name = format_ident!("{}", service.name);
+    let server_name = format_ident!("{}Server", service.name);
+    let client_name = format_ident!("{}Client", service.name);
+    let service_doc = doc(&service.comments);
+    let service_literal = &service.proto_name;
+    let methods_n = service.methods.len();
+    let methods_literal = service.methods.iter().map(|m| &m.name);
+
+    let codec = quote! { rspc::codec::ProstCodec };
+
+    let trait_methods = service.methods.iter().map(|m| {
+        let name = format_ident!("{}", m.name);
+        let method_doc = doc(&m.comments);
+        let request = rust_type(&m.input_type);
+        let reply = rust_type(&m.output_type);
+        let (args, ret) = match (m.client_streaming, m.server_streaming) {
+            (false, false) => (quote! { request: #request }, quote! { #reply }),
+            (true, false) => (
+                quote! { requests: rspc::server::RequestStream<#request> },
+                quote! { #reply },
+            ),
+            (false, true) => (
+                quote! { request: #request, replies: rspc::server::ReplySink<#reply> },
+                quote! { () },
+            ),
+            (true, true) => (
+                quote! {
+                    requests: rspc::server::RequestStream<#request>,
+                    replies: rspc::server::ReplySink<#reply>
+                },
+                quote! { () },
+            ),
+        };
+        quote! {
+            #method_doc
+            async fn #name(
+                &self,
+                #args,
+                ctx: rspc::server::CallContext,
+            ) -> Result<#ret, rspc::protocol::Status>;
+        }
+    });
+
+    let server_arms = service.methods.iter().zip(0u32..).map(|(m, id)| {
+        let name = format_ident!("{}", m.name);
+        let request = rust_type(&m.input_type);
+        let reply = rust_type(&m.output_type);
+        let read_request = quote! {
+            let request = match stream.read_unary().await.and_then(|request| {
+                <#codec as rspc::codec::Codec<#request>>::decode(request)
+            }) {
+                Ok(request) => request,
+                Err(status) => return stream.write_status(&status).await,
+            };
+        };
+        let write_reply = |writer: TokenStream| {
+            quote! {
+                match reply.and_then(|reply| <#codec as rspc::codec::Codec<#reply>>::encode(&reply)) {
+                    Ok(body) => #writer.write_last(0, body).await,
+                    Err(status) => #writer.write_status(&status).await,
+                }
+            }
+        };
+        let call = match (m.client_streaming, m.server_streaming) {
+            (false, false) => {
+                let write_reply = write_reply(quote! { stream });
+                quote! {
+                    #read_request
+                    let reply = self.inner.#name(request, ctx).await;
+                    #write_reply
+                }
+            }
+            (true, false) => {
+                let write_reply = write_reply(quote! { writer });
+                quote! {
+                    let (reader, writer) = stream.split();
+                    let requests = rspc::server::RequestStream::<#request, #codec>::new(reader);
+                    let reply = self.inner.#name(requests, ctx).await;
+                    #write_reply
+                }
+            }
+            (false, true) => quote! {
+                #read_request
+                let (_, writer) = stream.split();
+                let replies = rspc::server::ReplySink::<#reply, #codec>::new(writer.clone());
+                let result = self.inner.#name(request, replies, ctx).await;
+                writer.finish(result).await
+            },
+            (true, true) => quote! {
+                let (reader, writer) = stream.split();
+                let requests = rspc::server::RequestStream::<#request, #codec>::new(reader);
+                let replies = rspc::server::ReplySink::<#reply, #codec>::new(writer.clone());
+                let result = self.inner.#name(requests, replies, ctx).await;
+                writer.finish(result).await
+            },
+        };
+        quote! {
+            #id => {
+                let content_type = <#codec as rspc::codec::ContentType>::CONTENT_TYPE;
+                if let Err(status) = ctx.check_content_type(content_type) {
+                    return stream.write_status(&status).await;
+                }
+                #call
+            }
+        }
+    });
+
+    let client_methods = service.methods.iter().zip(0u32..).map(|(m, id)| {
+        let name = format_ident!("{}", m.name);
+        let method_doc = doc(&m.comments);
+        let request = rust_type(&m.input_type);
+        let reply = rust_type(&m.output_type);
+        let error = quote! { rspc::client::ClientError };
+        let stream = quote! { rspc::client::ReplyStream<#reply, #codec> };
+        let idempotent = is_idempotent(m);
+        let method = match (m.client_streaming, m.server_streaming) {
+            (false, false) => quote! {
+                pub async fn #name(&self, request: #request) -> Result<#reply, #error> {
+                    let method_id = self.method_id(#id);
+                    rspc::client::call_unary::<#codec, _, _>(&*self.channel, method_id, &request, #idempotent)
+                        .await
+                }
+            },
+            (true, false) => quote! {
+                pub async fn #name(
+                    &self,
+                    requests: impl futures::Stream<Item = #request>,
+                ) -> Result<#reply, #error> {
+                    let rw = self.channel.call_method(self.method_id(#id)).await?;
+                    rspc::client::call_client_stream::<#codec, _, _>(rw, requests).await
+                }
+            },
+            (false, true) => quote! {
+                pub async fn #name(&self, request: #request) -> Result<#stream, #error> {
+                    let rw = self.channel.call_method(self.method_id(#id)).await?;
+                    rspc::client::call_server_stream::<#codec, _, _>(rw, &request).await
+                }
+            },
+            (true, true) => quote! {
+                pub async fn #name(
+                    &self,
+                    requests: impl futures::Stream<Item = #request> + Send + 'static,
+                ) -> Result<#stream, #error> {
+                    let rw = self.channel.call_method(self.method_id(#id)).await?;
+                    rspc::client::call_bidi::<#codec, _, _>(rw, requests).await
+                }
+            },
+        };
+        quote! {
+            #method_doc
+            #method
+        }
+    });
+
+    quote! {
+        #service_doc
+        #[async_trait::async_trait(?Send)]
+        pub trait #trait_name {
+            #(#trait_methods)*
+        }
+
+        pub struct #server_name<T> {
+            inner: T,
+        }
+
+        impl<T> #server_name<T> {
+            pub fn new(inner: T) -> Self {
+                Self { inner }
+            }
+
+            pub fn into_inner(self) -> T {
+                self.inner
+            }
+        }
+
+        #[async_trait::async_trait(?Send)]
+        impl<T: #trait_name> rspc::server::Service for #server_name<T> {
+            async fn call_method(
+                &self,
+                fn_n: u32,
+                ctx: rspc::server::CallContext,
+                mut stream: rspc::server::ServerReaderWriter,
+            ) -> Result<(), rspc::server::ServerError> {
+                match fn_n {
+                    #(#server_arms)*
+                    _ => Err(rspc::server::ServerError::ErrorServiceMethodId()),
+                }
+            }
+
+            fn service_name(&self) -> &'static str {
+                #service_literal
+            }
+
+            fn methods_name(&self) -> &'static [&'static str] {
+                &[#(#methods_literal,)*]
+            }
+
+            fn methods_len(&self) -> usize {
+                #methods_n
+            }
+        }
+
+        #service_doc
+        #[derive(Clone)]
+        pub struct #client_name {
+            channel: rspc::client::SharedChannel,
+            first_method_id: u32,
+        }
+
+        impl #client_name {
+            pub fn new(channel: impl rspc::client::ClientChannel + 'static, first_method_id: u32) -> Self {
+                Self::with_shared(std::sync::Arc::new(channel), first_method_id)
+            }
+
+            /// A stub on a channel other stubs already share.
+            pub fn with_shared(channel: rspc::client::SharedChannel, first_method_id: u32) -> Self {
+                Self {
+                    channel,
+                    first_method_id,
+                }
+            }
+
+            #(#client_methods)*
+
+            fn method_id(&self, n: u32) -> u32 {
+                self.first_method_id + n
+            }
+        }
+
+        impl rspc::client::ClientStub for #client_name {
+            fn channel(&self) -> &'_ dyn rspc::client::ClientChannel {
+                &*self.channel
+            }
+
+            fn first_method_id(&self) -> u32 {
+                self.first_method_id
+            }
+        }
+    }
+}
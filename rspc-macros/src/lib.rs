@@ -1,83 +1,251 @@
 extern crate proc_macro;
 use proc_macro::TokenStream;
-use quote::{format_ident, quote};
+use quote::{format_ident, quote, quote_spanned};
 use syn::{
-    self,
+    self, parenthesized,
     parse::{Parse, ParseStream},
-    parse_macro_input, DeriveInput, Ident, Token,
+    parse_macro_input, token, DeriveInput, Ident, ItemTrait, Path, Token, Type,
 };
 
+use method::{Kind, MethodGen};
+
+mod method;
+mod service;
+
+#[derive(Debug)]
+struct RpcMethod {
+    name: Ident,
+    kind: Kind,
+    // takes an extra `FromCallContext` argument on the server
+    ctx: bool,
+    // `name(Request) -> Reply`, encoded with `codec` or the service codec
+    request: Option<Type>,
+    reply: Option<Type>,
+    codec: Option<Path>,
+    // unary calls may be retried or hedged
+    idempotent: bool,
+}
+
 #[derive(Debug)]
 struct RpcMethods {
-    normal: Vec<Ident>,
-    stream: Vec<Ident>,
+    codec: Option<Path>,
+    // unary methods, they get the first method ids
+    normal: Vec<RpcMethod>,
+    // every streaming kind, in declaration order
+    stream: Vec<RpcMethod>,
 }
 
 impl Parse for RpcMethods {
     fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut codec = None;
         let mut normal = vec![];
         let mut stream = vec![];
-        loop {
-            let first = input.parse::<Ident>()?;
-            if first.to_string() == "stream" {
-                let second = input.parse::<Ident>()?;
-                stream.push(second);
+        while !input.is_empty() {
+            if input.peek(Ident) && input.peek2(Token![=]) {
+                let option = input.parse::<Ident>()?;
+                input.parse::<Token![=]>()?;
+                if option == "codec" {
+                    codec = Some(input.parse::<Path>()?);
+                } else {
+                    return Err(syn::Error::new(option.span(), "unknown service option"));
+                }
             } else {
-                normal.push(first);
+                let method = Self::parse_method(input)?;
+                if method.kind == Kind::Unary {
+                    normal.push(method);
+                } else {
+                    stream.push(method);
+                }
             }
             if input.is_empty() {
                 break;
             }
             input.parse::<Token![,]>()?;
         }
-        Ok(Self { normal, stream })
+        let methods = Self {
+            codec,
+            normal,
+            stream,
+        };
+        methods.check_duplicate()?;
+        Ok(methods)
+    }
+}
+
+impl RpcMethods {
+    fn parse_method(input: ParseStream) -> syn::Result<RpcMethod> {
+        let mut kind = Kind::Unary;
+        let mut ctx = false;
+        let mut idempotent = false;
+        let mut codec = None;
+        let mut request = None;
+        let mut reply = None;
+        let name = loop {
+            let ident = input.parse::<Ident>()?;
+            if input.peek(token::Paren) {
+                let content;
+                parenthesized!(content in input);
+                if input.peek(Token![->]) {
+                    // `name(Request) -> Reply`
+                    request = Some(content.parse::<Type>()?);
+                    input.parse::<Token![->]>()?;
+                    reply = Some(input.parse::<Type>()?);
+                    break ident;
+                } else if ident == "codec" {
+                    codec = Some(content.parse::<Path>()?);
+                } else {
+                    return Err(syn::Error::new(ident.span(), "unknown method modifier"));
+                }
+            } else if input.peek(Ident) {
+                if let Some(k) = Kind::from_modifier(&ident) {
+                    if kind != Kind::Unary {
+                        return Err(syn::Error::new(ident.span(), "method kind given twice"));
+                    }
+                    kind = k;
+                } else if ident == "ctx" {
+                    ctx = true;
+                } else if ident == "idempotent" {
+                    idempotent = true;
+                } else {
+                    return Err(syn::Error::new(ident.span(), "unknown method modifier"));
+                }
+            } else {
+                break ident;
+            }
+        };
+
+        if kind == Kind::Raw && request.is_some() {
+            return Err(syn::Error::new(
+                name.span(),
+                "stream method takes the raw stream, remove `(Request) -> Reply`",
+            ));
+        }
+        if idempotent && kind != Kind::Unary {
+            return Err(syn::Error::new(
+                name.span(),
+                "only unary methods can be idempotent",
+            ));
+        }
+        if kind.is_typed_stream() && request.is_none() {
+            return Err(syn::Error::new(
+                name.span(),
+                "streaming method needs `(Request) -> Reply`",
+            ));
+        }
+
+        Ok(RpcMethod {
+            name,
+            kind,
+            ctx,
+            request,
+            reply,
+            codec,
+            idempotent,
+        })
+    }
+
+    fn check_duplicate(&self) -> syn::Result<()> {
+        let mut seen = std::collections::HashSet::new();
+        for m in self.normal.iter().chain(self.stream.iter()) {
+            if !seen.insert(m.name.to_string()) {
+                return Err(syn::Error::new(
+                    m.name.span(),
+                    format!("method `{}` is declared twice", m.name),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Methods in method id order.
+    fn methods(&self) -> impl Iterator<Item = &RpcMethod> {
+        self.normal.iter().chain(self.stream.iter())
     }
+
+    fn gen<'a>(&self, method: &'a RpcMethod) -> MethodGen<'a> {
+        let codec = match (&method.codec, &self.codec) {
+            (Some(codec), _) | (None, Some(codec)) => quote! { #codec },
+            (None, None) => quote! { rspc::codec::ProstCodec },
+        };
+        MethodGen {
+            name: &method.name,
+            kind: method.kind,
+            ctx: method.ctx,
+            request: method.request.as_ref(),
+            reply: method.reply.as_ref(),
+            codec,
+            idempotent: method.idempotent,
+        }
+    }
+}
+
+/// Define a service once as a trait, generating `<Trait>Server<T>`,
+/// `<Trait>Client` and the `<trait>_methods` id constants.
+///
+/// Unary methods are `async fn name(&self, request: Req) -> Result<Reply, Status>`,
+/// `#[client_stream]`, `#[server_stream]` and `#[bidi]` methods take a
+/// `RequestStream` and/or `ReplySink`, `#[stream]` ones the raw
+/// `ServerReaderWriter`. Any may take one more `FromCallContext` argument.
+/// The codec is set with `#[service(codec = Path)]` or per method with
+/// `#[codec(Path)]`. Unary methods marked `#[idempotent]` may be retried
+/// by the client channel. `#[service(blocking)]` also generates
+/// `<Trait>BlockingClient` on a `BlockingChannel`.
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as service::ServiceArgs);
+    let item = parse_macro_input!(item as ItemTrait);
+    service::expand(args, item)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
 }
 
 #[proc_macro_attribute]
 pub fn rspc_client(attr: TokenStream, item: TokenStream) -> TokenStream {
     let attr = parse_macro_input!(attr as RpcMethods);
+    let client = {
+        let item = item.clone();
+        parse_macro_input!(item as DeriveInput)
+    };
+    let (impl_generics, ty_generics, where_clause) = client.generics.split_for_impl();
+    let name = &client.ident;
 
-    println!("{:?}", attr);
-
-    let client: DeriveInput = syn::parse(item.clone()).unwrap();
-    let generics = client.generics;
-    let name = client.ident;
-
-    let normal = attr.normal.iter();
-    let normal_n = normal.len() as u32;
-    let normal_id = 0..normal_n;
-    let stream = attr.stream.iter();
-    let stream_n = stream.len() as u32;
-    let stream_id = (0..stream_n).map(|x| x + normal_n);
-    let stream_impl = attr.stream.iter().map(|i| format_ident!("{}_impl", i));
+    let methods = attr.methods().zip(0u32..).map(|(m, id)| {
+        let name = &m.name;
+        let channel = quote! { rspc::client::ClientStub::channel(self) };
+        let method_id = quote! { rspc::client::ClientStub::first_method_id(self) + #id };
+        if let Some(method) = attr.gen(m).client_method(&channel, &method_id) {
+            return method;
+        }
+        let open = quote! { #channel.call_method(#method_id).await };
+        if m.kind == Kind::Unary {
+            let idempotent = m.idempotent;
+            return quote! {
+                pub async fn #name(&self, request: bytes::Bytes) -> Result<(u32, bytes::Bytes), rspc::client::ClientError> {
+                    let mut call = rspc::client::UnaryCall::new(#method_id, request);
+                    call.idempotent = #idempotent;
+                    #channel.unary(&call).await
+                }
+            };
+        }
+        let stream_impl = format_ident!("{}_impl", m.name);
+        let call = quote_spanned! {name.span()=>
+            let ret: Result<(), rspc::client::ClientError> = self.#stream_impl(rw).await;
+        };
+        quote! {
+            pub async fn #name(&self) -> Result<(), rspc::client::ClientError> {
+                let rw: rspc::client::ClientReaderWriter = #open?;
+                #call
+                ret
+            }
+        }
+    });
 
     let ret = quote! {
-        impl #generics #name #generics {
-            #(
-                pub async fn #normal(&self, request: bytes::Bytes) -> Result<(u32, bytes::Bytes), rspc::client::ClientError> {
-                    let id = rspc::client::ClientStub::first_method_id(self);
-                    let mut rw: rspc::client::ClientReaderWriter =
-                        rspc::client::ClientStub::channel(self).call_method(id + #normal_id);
-
-                    rw.write_last(request).await?;
-                    Ok(rw.read().await.unwrap())
-                }
-            )*
-
-            #(
-                pub async fn #stream(&self) {
-                    let id = rspc::client::ClientStub::first_method_id(self);
-                    let rw: rspc::client::ClientReaderWriter =
-                        rspc::client::ClientStub::channel(self).call_method(id + #stream_id);
-                    self.#stream_impl(rw).await
-                }
-            )*
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#methods)*
         }
     };
 
-    println!("{}", ret);
-
     let mut item = item;
     let t: TokenStream = ret.into();
     item.extend(t);
@@ -87,53 +255,43 @@ pub fn rspc_client(attr: TokenStream, item: TokenStream) -> TokenStream {
 #[proc_macro_attribute]
 pub fn rspc_server(attr: TokenStream, item: TokenStream) -> TokenStream {
     let attr = parse_macro_input!(attr as RpcMethods);
-    println!("{:?}", attr);
-
-    let server: DeriveInput = syn::parse(item.clone()).unwrap();
-    let generics = server.generics;
-    let name = server.ident;
+    let server = {
+        let item = item.clone();
+        parse_macro_input!(item as DeriveInput)
+    };
+    let (impl_generics, ty_generics, where_clause) = server.generics.split_for_impl();
+    let name = &server.ident;
     let name_literal = name.to_string();
 
-    let normal = attr.normal.iter();
-    let normal_n = normal.len() as u32;
-    let normal_id = 0..normal_n;
-    let normal_literal = normal.map(|x| x.to_string());
-    let stream = attr.stream.iter();
-    let stream_n = stream.len() as u32;
-    let stream_id = (0..stream_n).map(|x| x + normal_n);
-    let stream_literal = stream.map(|x| x.to_string());
+    let methods_n = (attr.normal.len() + attr.stream.len()) as u32;
+    let method_id = 0..methods_n;
+    let method_literal = attr.methods().map(|m| m.name.to_string());
+    let ctx = if attr.methods().any(|m| attr.gen(m).uses_ctx()) {
+        quote! { ctx }
+    } else {
+        quote! { _ctx }
+    };
+    let method_call = attr.methods().map(|m| {
+        let name = &m.name;
+        attr.gen(m)
+            .server_arm(&quote! { Self::#name }, &quote! { self })
+    });
 
     let ret = quote! {
         #[async_trait::async_trait(?Send)]
-        impl #generics rspc::server::Service for #name #generics {
+        impl #impl_generics rspc::server::Service for #name #ty_generics #where_clause {
             async fn call_method(
                 &self,
                 fn_n: u32,
+                #ctx: rspc::server::CallContext,
                 mut stream: rspc::server::ServerReaderWriter,
             ) -> Result<(), rspc::server::ServerError> {
-                if fn_n < #normal_n {
-                    if let Some(request) = stream.read().await {
-                        let reply = match fn_n {
-                            #(
-                                #normal_id => self.hello(request).await,
-                            )*
-
-                            _ => return Err(rspc::server::ServerError::NormalRpcMethodError()),
-                        };
-
-                        stream.write(reply.0, reply.1).await?;
-                        Ok(())
-                    } else {
-                        Err(rspc::server::ServerError::NormalRpcMethodError())
-                    }
-                } else {
-                    match fn_n {
-                        #(
-                            #stream_id => self.hello_stream(stream).await,
-                        )*
+                match fn_n {
+                    #(
+                        #method_id => #method_call,
+                    )*
 
-                        _ => Err(rspc::server::ServerError::StreamRpcMethodError()),
-                    }
+                    _ => Err(rspc::server::ServerError::ErrorServiceMethodId()),
                 }
             }
 
@@ -142,20 +300,15 @@ pub fn rspc_server(attr: TokenStream, item: TokenStream) -> TokenStream {
             }
 
             fn methods_name(&self) -> &'static [&'static str] {
-                &[
-                    #(#normal_literal,)*
-                    #(#stream_literal,)*
-                ]
+                &[#(#method_literal,)*]
             }
 
             fn methods_len(&self) -> usize {
-                (#normal_n + #stream_n) as usize
+                #methods_n as usize
             }
         }
     };
 
-    println!("{}", ret);
-
     let mut item = item;
     let t: TokenStream = ret.into();
     item.extend(t);
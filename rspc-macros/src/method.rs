@@ -0,0 +1,301 @@
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+use syn::{Ident, Type};
+
+/// How requests and replies of a method are carried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    // one request, one reply
+    Unary,
+    // request stream, one reply
+    ClientStream,
+    // one request, reply stream
+    ServerStream,
+    // request stream and reply stream
+    Bidi,
+    // the handler gets the raw `ServerReaderWriter`
+    Raw,
+}
+
+impl Kind {
+    pub fn from_modifier(ident: &Ident) -> Option<Kind> {
+        match ident.to_string().as_str() {
+            "stream" => Some(Kind::Raw),
+            "client_stream" => Some(Kind::ClientStream),
+            "server_stream" => Some(Kind::ServerStream),
+            "bidi" => Some(Kind::Bidi),
+            _ => None,
+        }
+    }
+
+    /// Typed kinds other than unary need `(Request) -> Reply`.
+    pub fn is_typed_stream(self) -> bool {
+        matches!(self, Kind::ClientStream | Kind::ServerStream | Kind::Bidi)
+    }
+}
+
+/// The pieces shared by the server dispatch and client stub of a method.
+pub struct MethodGen<'a> {
+    pub name: &'a Ident,
+    pub kind: Kind,
+    pub ctx: bool,
+    pub request: Option<&'a Type>,
+    pub reply: Option<&'a Type>,
+    pub codec: TokenStream,
+    // unary calls may be retried
+    pub idempotent: bool,
+}
+
+impl<'a> MethodGen<'a> {
+    /// Whether `server_arm` reads `ctx`, typed methods check the content type.
+    pub fn uses_ctx(&self) -> bool {
+        self.ctx || (self.request.is_some() && self.reply.is_some())
+    }
+
+    /// A block evaluating to `Result<(), ServerError>`, with `ctx` and
+    /// `stream` in scope. `callee` and `receiver` form the handler call
+    /// `callee(receiver, args..)`.
+    pub fn server_arm(&self, callee: &TokenStream, receiver: &TokenStream) -> TokenStream {
+        let MethodGen {
+            name,
+            kind,
+            ctx,
+            request,
+            reply,
+            codec,
+            ..
+        } = self;
+
+        let extract = if *ctx {
+            quote! {
+                let arg = match rspc::server::FromCallContext::from_call_context(&ctx) {
+                    Ok(arg) => arg,
+                    Err(status) => return stream.write_status(&status).await,
+                };
+            }
+        } else {
+            quote! {}
+        };
+        let call = |args: TokenStream| {
+            let arg = if *ctx {
+                quote! { , arg }
+            } else {
+                quote! {}
+            };
+            quote_spanned! {name.span()=> #callee(#receiver, #args #arg).await }
+        };
+        let check_content_type = quote! {
+            let content_type = <#codec as rspc::codec::ContentType>::CONTENT_TYPE;
+            if let Err(status) = ctx.check_content_type(content_type) {
+                return stream.write_status(&status).await;
+            }
+        };
+        let read_request = |request: &Type| {
+            quote! {
+                let request = match stream.read_unary().await.and_then(|request| {
+                    <#codec as rspc::codec::Codec<#request>>::decode(request)
+                }) {
+                    Ok(request) => request,
+                    Err(status) => return stream.write_status(&status).await,
+                };
+            }
+        };
+        let write_reply = |reply: &Type, writer: TokenStream| {
+            quote! {
+                match reply.and_then(|reply| <#codec as rspc::codec::Codec<#reply>>::encode(&reply)) {
+                    Ok(body) => #writer.write_last(0, body).await,
+                    Err(status) => #writer.write_status(&status).await,
+                }
+            }
+        };
+
+        match (kind, request, reply) {
+            (Kind::Unary, Some(request), Some(reply)) => {
+                let read_request = read_request(request);
+                let call = call(quote! { request });
+                let write_reply = write_reply(reply, quote! { stream });
+                quote! {{
+                    #extract
+                    #check_content_type
+                    #read_request
+                    let reply: Result<#reply, rspc::protocol::Status> = #call;
+                    #write_reply
+                }}
+            }
+            (Kind::Unary, _, _) => {
+                let call = call(quote! { request });
+                let reply = quote_spanned! {name.span()=>
+                    let reply: (u32, bytes::Bytes) = #call;
+                };
+                quote! {{
+                    #extract
+                    let request = match stream.read_unary().await {
+                        Ok(request) => request,
+                        Err(status) => return stream.write_status(&status).await,
+                    };
+                    #reply
+                    stream.write_last(reply.0, reply.1).await
+                }}
+            }
+            (Kind::ClientStream, Some(request), Some(reply)) => {
+                let call = call(quote! { requests });
+                let write_reply = write_reply(reply, quote! { writer });
+                quote! {{
+                    #extract
+                    #check_content_type
+                    let (reader, writer) = stream.split();
+                    let requests = rspc::server::RequestStream::<#request, #codec>::new(reader);
+                    let reply: Result<#reply, rspc::protocol::Status> = #call;
+                    #write_reply
+                }}
+            }
+            (Kind::ServerStream, Some(request), Some(reply)) => {
+                let read_request = read_request(request);
+                let call = call(quote! { request, replies });
+                quote! {{
+                    #extract
+                    #check_content_type
+                    #read_request
+                    let (_, writer) = stream.split();
+                    let replies = rspc::server::ReplySink::<#reply, #codec>::new(writer.clone());
+                    let result: Result<(), rspc::protocol::Status> = #call;
+                    writer.finish(result).await
+                }}
+            }
+            (Kind::Bidi, Some(request), Some(reply)) => {
+                let call = call(quote! { requests, replies });
+                quote! {{
+                    #extract
+                    #check_content_type
+                    let (reader, writer) = stream.split();
+                    let requests = rspc::server::RequestStream::<#request, #codec>::new(reader);
+                    let replies = rspc::server::ReplySink::<#reply, #codec>::new(writer.clone());
+                    let result: Result<(), rspc::protocol::Status> = #call;
+                    writer.finish(result).await
+                }}
+            }
+            _ => {
+                let call = call(quote! { stream });
+                quote! {{
+                    #extract
+                    let ret: Result<(), rspc::server::ServerError> = #call;
+                    ret
+                }}
+            }
+        }
+    }
+
+    /// A typed client stub method, `channel` evaluates to
+    /// `&dyn ClientChannel` and `method_id` to the absolute method id.
+    /// `None` for raw methods.
+    pub fn client_method(
+        &self,
+        channel: &TokenStream,
+        method_id: &TokenStream,
+    ) -> Option<TokenStream> {
+        let MethodGen {
+            name,
+            kind,
+            request,
+            reply,
+            codec,
+            idempotent,
+            ..
+        } = self;
+        let request = (*request)?;
+        let reply = (*reply)?;
+        let error = quote! { rspc::client::ClientError };
+        let open = quote! { #channel.call_method(#method_id).await };
+        let stream = quote! { rspc::client::ReplyStream<#reply, #codec> };
+        Some(match kind {
+            Kind::Unary => quote! {
+                pub async fn #name(&self, request: #request) -> Result<#reply, #error> {
+                    rspc::client::call_unary::<#codec, _, _>(#channel, #method_id, &request, #idempotent)
+                        .await
+                }
+            },
+            Kind::ClientStream => quote! {
+                pub async fn #name(
+                    &self,
+                    requests: impl futures::Stream<Item = #request>,
+                ) -> Result<#reply, #error> {
+                    let rw = #open?;
+                    rspc::client::call_client_stream::<#codec, _, _>(rw, requests).await
+                }
+            },
+            Kind::ServerStream => quote! {
+                pub async fn #name(&self, request: #request) -> Result<#stream, #error> {
+                    let rw = #open?;
+                    rspc::client::call_server_stream::<#codec, _, _>(rw, &request).await
+                }
+            },
+            Kind::Bidi => quote! {
+                pub async fn #name(
+                    &self,
+                    requests: impl futures::Stream<Item = #request> + Send + 'static,
+                ) -> Result<#stream, #error> {
+                    let rw = #open?;
+                    rspc::client::call_bidi::<#codec, _, _>(rw, requests).await
+                }
+            },
+            Kind::Raw => return None,
+        })
+    }
+
+    /// A blocking client stub method, calling the async stub `inner` on
+    /// the `BlockingChannel` `channel`.
+    pub fn blocking_client_method(
+        &self,
+        inner: &TokenStream,
+        channel: &TokenStream,
+    ) -> TokenStream {
+        let MethodGen {
+            name,
+            kind,
+            request,
+            reply,
+            codec,
+            ..
+        } = self;
+        let error = quote! { rspc::client::ClientError };
+        let stream = quote! { rspc::client::BlockingStream<#reply, #codec> };
+        match kind {
+            Kind::Unary => quote! {
+                pub fn #name(&self, request: #request) -> Result<#reply, #error> {
+                    #channel.block_on(#inner.#name(request))
+                }
+            },
+            Kind::ClientStream => quote! {
+                pub fn #name(
+                    &self,
+                    requests: impl IntoIterator<Item = #request>,
+                ) -> Result<#reply, #error> {
+                    #channel.block_on(#inner.#name(futures::stream::iter(requests)))
+                }
+            },
+            Kind::ServerStream => quote! {
+                pub fn #name(&self, request: #request) -> Result<#stream, #error> {
+                    let replies = #channel.block_on(#inner.#name(request))?;
+                    Ok(rspc::client::BlockingStream::new(replies, #channel.clone()))
+                }
+            },
+            // `requests` is iterated on a thread of its own, a request may
+            // wait on the replies to the ones before
+            Kind::Bidi => quote! {
+                pub fn #name(
+                    &self,
+                    requests: impl IntoIterator<Item = #request> + Send + 'static,
+                ) -> Result<#stream, #error> {
+                    let requests = rspc::client::request_stream(requests);
+                    let replies = #channel.block_on(#inner.#name(requests))?;
+                    Ok(rspc::client::BlockingStream::new(replies, #channel.clone()))
+                }
+            },
+            Kind::Raw => quote! {
+                pub fn #name(&self) -> Result<rspc::client::ClientReaderWriter, #error> {
+                    #channel.block_on(#inner.#name())
+                }
+            },
+        }
+    }
+}
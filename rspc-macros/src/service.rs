@@ -0,0 +1,442 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+
+use crate::method::{Kind, MethodGen};
+use syn::{
+    parse::{Parse, ParseStream},
+    spanned::Spanned,
+    FnArg, GenericArgument, Ident, ItemTrait, Path, PathArguments, ReturnType, Token, TraitItem,
+    TraitItemMethod, Type,
+};
+
+/// `#[rspc::service(codec = Path, blocking)]`
+pub struct ServiceArgs {
+    codec: Option<Path>,
+    // also generate `<Trait>BlockingClient`
+    blocking: bool,
+}
+
+impl Parse for ServiceArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut codec = None;
+        let mut blocking = false;
+        while !input.is_empty() {
+            let option = input.parse::<Ident>()?;
+            if option == "codec" {
+                input.parse::<Token![=]>()?;
+                codec = Some(input.parse::<Path>()?);
+            } else if option == "blocking" {
+                blocking = true;
+            } else {
+                return Err(syn::Error::new(option.span(), "unknown service option"));
+            }
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+        Ok(Self { codec, blocking })
+    }
+}
+
+/// Inherent methods of the generated clients, an rpc method may not share
+/// their names.
+const CLIENT_METHODS: &[&str] = &["new", "with_shared", "method_id"];
+
+struct ServiceMethod {
+    name: Ident,
+    kind: Kind,
+    // last argument, extracted with `FromCallContext`
+    ctx: bool,
+    // request and reply types, `None` for `#[stream]` methods
+    request: Option<Type>,
+    reply: Option<Type>,
+    codec: Option<Path>,
+    idempotent: bool,
+}
+
+impl ServiceMethod {
+    /// Take the rspc attributes off `method` and check its signature.
+    fn parse(method: &mut TraitItemMethod) -> syn::Result<Self> {
+        let mut kind = Kind::Unary;
+        let mut codec = None;
+        let mut idempotent = false;
+        let mut attrs = vec![];
+        for attr in method.attrs.drain(..) {
+            let k = attr.path.get_ident().and_then(Kind::from_modifier);
+            if let Some(k) = k {
+                if kind != Kind::Unary {
+                    return Err(syn::Error::new(attr.span(), "method kind given twice"));
+                }
+                kind = k;
+            } else if attr.path.is_ident("codec") {
+                codec = Some(attr.parse_args::<Path>()?);
+            } else if attr.path.is_ident("idempotent") {
+                idempotent = true;
+            } else {
+                attrs.push(attr);
+            }
+        }
+        method.attrs = attrs;
+        if idempotent && kind != Kind::Unary {
+            return Err(syn::Error::new(
+                method.sig.ident.span(),
+                "only unary methods can be idempotent",
+            ));
+        }
+
+        let sig = &method.sig;
+        if CLIENT_METHODS.contains(&sig.ident.to_string().as_str()) {
+            return Err(syn::Error::new(
+                sig.ident.span(),
+                format!(
+                    "`{}` is a method of the generated client, rename the rpc method",
+                    sig.ident
+                ),
+            ));
+        }
+        if sig.asyncness.is_none() {
+            return Err(syn::Error::new(
+                sig.fn_token.span(),
+                "rpc method must be async",
+            ));
+        }
+        if !sig.generics.params.is_empty() {
+            return Err(syn::Error::new(
+                sig.generics.span(),
+                "rpc method can not be generic",
+            ));
+        }
+        let mut inputs = sig.inputs.iter();
+        match inputs.next() {
+            Some(FnArg::Receiver(r)) if r.reference.is_some() && r.mutability.is_none() => {}
+            _ => {
+                return Err(syn::Error::new(
+                    sig.inputs.span(),
+                    "rpc method must take `&self`",
+                ))
+            }
+        }
+        let args: Vec<&Type> = inputs
+            .map(|arg| match arg {
+                FnArg::Typed(arg) => Ok(&*arg.ty),
+                FnArg::Receiver(r) => Err(syn::Error::new(r.span(), "unexpected receiver")),
+            })
+            .collect::<syn::Result<_>>()?;
+
+        let expect = match kind {
+            Kind::Unary => "request: Req",
+            Kind::ClientStream => "requests: RequestStream<Req>",
+            Kind::ServerStream => "request: Req, replies: ReplySink<Reply>",
+            Kind::Bidi => "requests: RequestStream<Req>, replies: ReplySink<Reply>",
+            Kind::Raw => "stream: ServerReaderWriter",
+        };
+        let base = match kind {
+            Kind::ServerStream | Kind::Bidi => 2,
+            _ => 1,
+        };
+        let ctx = match args.len() {
+            n if n == base => false,
+            n if n == base + 1 => true,
+            _ => {
+                return Err(syn::Error::new(
+                    sig.inputs.span(),
+                    format!(
+                        "rpc method arguments should be `(&self, {}[, ctx: impl FromCallContext])`",
+                        expect
+                    ),
+                ))
+            }
+        };
+        let generic_of = |ty: &Type, wrapper: &str| {
+            first_generic(ty, wrapper)
+                .ok_or_else(|| syn::Error::new(ty.span(), format!("expect `{}`", expect)))
+        };
+        let reply_of_result = || {
+            first_generic_of_return(&sig.output).ok_or_else(|| {
+                syn::Error::new(
+                    sig.output.span(),
+                    "rpc method must return `Result<Reply, Status>`",
+                )
+            })
+        };
+
+        let (request, reply) = match kind {
+            Kind::Unary => (Some(args[0].clone()), Some(reply_of_result()?)),
+            Kind::ClientStream => (
+                Some(generic_of(args[0], "RequestStream")?),
+                Some(reply_of_result()?),
+            ),
+            Kind::ServerStream => (
+                Some(args[0].clone()),
+                Some(generic_of(args[1], "ReplySink")?),
+            ),
+            Kind::Bidi => (
+                Some(generic_of(args[0], "RequestStream")?),
+                Some(generic_of(args[1], "ReplySink")?),
+            ),
+            Kind::Raw => (None, None),
+        };
+
+        Ok(Self {
+            name: sig.ident.clone(),
+            kind,
+            ctx,
+            request,
+            reply,
+            codec,
+            idempotent,
+        })
+    }
+
+    fn gen(&self, args: &ServiceArgs) -> MethodGen<'_> {
+        let codec = match (&self.codec, &args.codec) {
+            (Some(codec), _) | (None, Some(codec)) => quote! { #codec },
+            (None, None) => quote! { rspc::codec::ProstCodec },
+        };
+        MethodGen {
+            name: &self.name,
+            kind: self.kind,
+            ctx: self.ctx,
+            request: self.request.as_ref(),
+            reply: self.reply.as_ref(),
+            codec,
+            idempotent: self.idempotent,
+        }
+    }
+}
+
+fn first_generic_of_return(output: &ReturnType) -> Option<Type> {
+    match output {
+        ReturnType::Type(_, ty) => first_generic(ty, "Result"),
+        ReturnType::Default => None,
+    }
+}
+
+/// `T` of `Wrapper<T, ..>`.
+fn first_generic(ty: &Type, wrapper: &str) -> Option<Type> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != wrapper {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first()? {
+            GenericArgument::Type(ty) => Some(ty.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+pub fn expand(args: ServiceArgs, mut item: ItemTrait) -> syn::Result<TokenStream> {
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            item.generics.span(),
+            "rspc service trait can not be generic",
+        ));
+    }
+    let mut methods = vec![];
+    for trait_item in item.items.iter_mut() {
+        match trait_item {
+            TraitItem::Method(method) => methods.push(ServiceMethod::parse(method)?),
+            other => {
+                return Err(syn::Error::new(
+                    other.span(),
+                    "rspc service trait can only contain methods",
+                ))
+            }
+        }
+    }
+
+    let vis = &item.vis;
+    let trait_name = &item.ident;
+    let server_name = format_ident!("{}Server", trait_name);
+    let client_name = format_ident!("{}Client", trait_name);
+    let ids_name = format_ident!("{}_methods", snake_case(&trait_name.to_string()));
+    let name_literal = trait_name.to_string();
+    let methods_n = methods.len() as u32;
+    let methods_literal = methods.iter().map(|m| m.name.to_string());
+    let id_const = methods
+        .iter()
+        .map(|m| format_ident!("{}", m.name.to_string().to_uppercase()))
+        .collect::<Vec<_>>();
+    let id_value = 0..methods_n;
+    let ctx = if methods.iter().any(|m| m.gen(&args).uses_ctx()) {
+        quote! { ctx }
+    } else {
+        quote! { _ctx }
+    };
+    let server_arms = methods.iter().zip(&id_const).map(|(m, id)| {
+        let name = &m.name;
+        let arm = m.gen(&args).server_arm(
+            &quote! { <T as #trait_name>::#name },
+            &quote! { &self.inner },
+        );
+        quote! { #ids_name::#id => #arm, }
+    });
+
+    let client_methods = methods.iter().zip(&id_const).map(|(m, id)| {
+        let name = &m.name;
+        let channel = quote! { rspc::client::ClientStub::channel(self) };
+        let method_id = quote! { self.method_id(#ids_name::#id) };
+        m.gen(&args)
+            .client_method(&channel, &method_id)
+            .unwrap_or_else(|| {
+                quote! {
+                    pub async fn #name(&self) -> Result<rspc::client::ClientReaderWriter, rspc::client::ClientError> {
+                        #channel.call_method(#method_id).await
+                    }
+                }
+            })
+    });
+
+    let blocking_client = if args.blocking {
+        let blocking_name = format_ident!("{}BlockingClient", trait_name);
+        let blocking_methods = methods.iter().map(|m| {
+            m.gen(&args)
+                .blocking_client_method(&quote! { self.inner }, &quote! { self.channel })
+        });
+        let doc = format!("`{}` for code without an async runtime.", client_name);
+        quote! {
+            #[doc = #doc]
+            #[derive(Clone)]
+            #vis struct #blocking_name {
+                inner: #client_name,
+                channel: rspc::client::BlockingChannel,
+            }
+
+            impl #blocking_name {
+                pub fn new(channel: rspc::client::BlockingChannel, first_method_id: u32) -> Self {
+                    Self {
+                        inner: #client_name::with_shared(channel.channel().clone(), first_method_id),
+                        channel,
+                    }
+                }
+
+                #(#blocking_methods)*
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    Ok(quote! {
+        #[async_trait::async_trait(?Send)]
+        #item
+
+        /// Method ids relative to the first method id of the service.
+        #vis mod #ids_name {
+            #(pub const #id_const: u32 = #id_value;)*
+
+            pub const NAMES: &[&str] = &[#(#methods_literal,)*];
+        }
+
+        #vis struct #server_name<T> {
+            inner: T,
+        }
+
+        impl<T> #server_name<T> {
+            pub fn new(inner: T) -> Self {
+                Self { inner }
+            }
+
+            pub fn into_inner(self) -> T {
+                self.inner
+            }
+        }
+
+        #[async_trait::async_trait(?Send)]
+        impl<T: #trait_name> rspc::server::Service for #server_name<T> {
+            async fn call_method(
+                &self,
+                fn_n: u32,
+                #ctx: rspc::server::CallContext,
+                mut stream: rspc::server::ServerReaderWriter,
+            ) -> Result<(), rspc::server::ServerError> {
+                match fn_n {
+                    #(#server_arms)*
+                    _ => Err(rspc::server::ServerError::ErrorServiceMethodId()),
+                }
+            }
+
+            fn service_name(&self) -> &'static str {
+                #name_literal
+            }
+
+            fn methods_name(&self) -> &'static [&'static str] {
+                #ids_name::NAMES
+            }
+
+            fn methods_len(&self) -> usize {
+                #methods_n as usize
+            }
+        }
+
+        #[derive(Clone)]
+        #vis struct #client_name {
+            channel: rspc::client::SharedChannel,
+            first_method_id: u32,
+        }
+
+        impl #client_name {
+            pub fn new(channel: impl rspc::client::ClientChannel + 'static, first_method_id: u32) -> Self {
+                Self::with_shared(std::sync::Arc::new(channel), first_method_id)
+            }
+
+            /// A stub on a channel other stubs already share.
+            pub fn with_shared(channel: rspc::client::SharedChannel, first_method_id: u32) -> Self {
+                Self {
+                    channel,
+                    first_method_id,
+                }
+            }
+
+            #(#client_methods)*
+
+            fn method_id(&self, n: u32) -> u32 {
+                self.first_method_id + n
+            }
+        }
+
+        impl rspc::client::ClientStub for #client_name {
+            fn channel(&self) -> &'_ dyn rspc::client::ClientChannel {
+                &*self.channel
+            }
+
+            fn first_method_id(&self) -> u32 {
+                self.first_method_id
+            }
+        }
+
+        #blocking_client
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trait_name_to_snake_case() {
+        assert_eq!(snake_case("Hello"), "hello");
+        assert_eq!(snake_case("HelloWorld"), "hello_world");
+    }
+}
@@ -1,6 +1,10 @@
 use std::io::Result;
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["src/example/example.proto"], &["src/"])?;
-    println!("cargo:rerun-if-changed=src/example.proto");
+    rspc_build::compile_protos(
+        &["src/example/example.proto", "src/health/health.proto"],
+        &["src/"],
+    )?;
+    println!("cargo:rerun-if-changed=src/example/example.proto");
+    println!("cargo:rerun-if-changed=src/health/health.proto");
     Ok(())
 }
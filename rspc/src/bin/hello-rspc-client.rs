@@ -1,15 +1,33 @@
-use futures::join;
-use rspc::client::{Channel, ClientError, ClientStub};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
-#[rspc_macros::rspc_client(hello, stream hello_stream)]
-pub struct HelloClient<'a> {
-    channel: &'a rspc::client::RunningChannel,
+use bytes::Bytes;
+use futures::{join, stream, StreamExt};
+use rspc::{
+    client::{
+        ClientError, ClientStub, InterceptedChannel, Interceptor, InterceptorChain, ManagedChannel,
+        RetryChannel, RetryPolicy,
+    },
+    example::pb::{HelloReply, HelloRequest},
+    protocol::Metadata,
+};
+
+#[rspc_macros::rspc_client(
+    idempotent hello(HelloRequest) -> HelloReply,
+    stream hello_stream,
+    bidi hello_bidi(HelloRequest) -> HelloReply,
+)]
+#[derive(Clone)]
+pub struct HelloClient {
+    channel: rspc::client::SharedChannel,
     first_method_id: u32,
 }
 
-impl<'a> ClientStub for HelloClient<'a> {
-    fn channel(&self) -> &'_ rspc::client::RunningChannel {
-        self.channel
+impl ClientStub for HelloClient {
+    fn channel(&self) -> &'_ dyn rspc::client::ClientChannel {
+        &*self.channel
     }
 
     fn first_method_id(&self) -> u32 {
@@ -17,23 +35,48 @@ impl<'a> ClientStub for HelloClient<'a> {
     }
 }
 
-impl<'a> HelloClient<'a> {
-    pub fn new(channel: &'a rspc::client::RunningChannel, first_method_id: u32) -> Self {
+impl HelloClient {
+    pub fn new(channel: rspc::client::SharedChannel, first_method_id: u32) -> Self {
         Self {
             channel,
             first_method_id,
         }
     }
 
-    async fn hello_stream_impl(&self, mut rw: rspc::client::ClientReaderWriter) {
-        rw.write("stream hello1".into()).await.unwrap();
-        rw.write_last("stream hello2".into()).await.unwrap();
-        let r1 = rw.read().await;
-        let r2 = rw.read().await;
-        let r3 = rw.read().await;
+    async fn hello_stream_impl(
+        &self,
+        mut rw: rspc::client::ClientReaderWriter,
+    ) -> Result<(), ClientError> {
+        rw.write("stream hello1".into()).await?;
+        rw.write_last("stream hello2".into()).await?;
+        let r1 = rw.read().await?;
+        let r2 = rw.read().await?;
+        let r3 = rw.read().await?;
         println!("reply1 {:?}", r1);
         println!("reply2 {:?}", r2);
         println!("reply3 {:?}", r3);
+        Ok(())
+    }
+}
+
+/// Tags every call with a trace id and logs the reply statuses and errors.
+#[derive(Default)]
+struct CallTracer {
+    next: AtomicU64,
+}
+
+impl Interceptor for CallTracer {
+    fn on_metadata(&self, _method_id: u32, metadata: &mut Metadata) {
+        let trace_id = self.next.fetch_add(1, Ordering::Relaxed);
+        metadata.insert("trace-id", format!("hello-client-{}", trace_id));
+    }
+
+    fn on_reply(&self, method_id: u32, status_code: u32, _body: &Bytes) {
+        println!("reply of method {} status {}", method_id, status_code);
+    }
+
+    fn on_error(&self, method_id: u32, error: &ClientError) {
+        println!("call of method {} failed: {}", method_id, error);
     }
 }
 
@@ -47,21 +90,58 @@ async fn main() -> Result<(), ClientError> {
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    let channel = Channel::new("127.0.0.1:8080").await?;
-    let (run, channel) = channel.run();
-    let client = HelloClient::new(&channel, 0);
-    let client2 = HelloClient::new(&channel, 2);
+    let (run, channel) = ManagedChannel::new("127.0.0.1:8080").run();
+    let retry = RetryChannel::new(channel.clone(), RetryPolicy::default());
+    let traced =
+        InterceptedChannel::new(retry, InterceptorChain::new().with(CallTracer::default()));
+    let shared: rspc::client::SharedChannel = Arc::new(traced);
+    let client = HelloClient::new(shared.clone(), 0);
+    let client2 = HelloClient::new(shared, 3);
 
-    let f1 = client2.hello_stream();
+    let f1 = async {
+        if let Err(e) = client2.hello_stream().await {
+            println!("stream error {:?}", e)
+        }
+    };
     let f2 = async {
-        let t = client.hello("hello".into()).await;
+        let t = client
+            .hello(HelloRequest {
+                name: "client".into(),
+            })
+            .await;
         println!("normal reply {:?}", t)
     };
+    // stubs are Send, a call may run on another worker thread
+    let spawned = tokio::spawn({
+        let client2 = client2.clone();
+        async move {
+            client2
+                .hello(HelloRequest {
+                    name: "client2".into(),
+                })
+                .await
+        }
+    });
     let f3 = async {
-        let t = client2.hello("hello".into()).await;
-        println!("normal reply {:?}", t)
+        match spawned.await {
+            Ok(t) => println!("normal reply {:?}", t),
+            Err(e) => println!("call task failed {:?}", e),
+        }
     };
 
-    join!(run, f1, f2, f3).0?;
+    let f4 = async {
+        let requests =
+            stream::iter(["bidi1", "bidi2"]).map(|name| HelloRequest { name: name.into() });
+        match client.hello_bidi(requests).await {
+            Ok(replies) => println!("bidi replies {:?}", replies.collect::<Vec<_>>().await),
+            Err(e) => println!("bidi error {:?}", e),
+        }
+    };
+
+    let calls = async {
+        join!(f1, f2, f3, f4);
+        channel.shutdown();
+    };
+    join!(run, calls);
     Ok(())
 }
@@ -1,26 +1,43 @@
 use std::cell::Cell;
 
-use rspc::server::{Server, ServerError};
+use futures::StreamExt;
+use rspc::{
+    example::pb::{HelloReply, HelloRequest},
+    health::health_reporter,
+    protocol::Status,
+    server::{CallContext, Interceptor, ReplySink, RequestStream, Server, ServerError},
+};
 use tokio::task;
 
 // macros generate template
-#[rspc_macros::rspc_server(hello, stream hello_stream)]
+#[rspc_macros::rspc_server(
+    ctx hello(HelloRequest) -> HelloReply,
+    stream hello_stream,
+    bidi hello_bidi(HelloRequest) -> HelloReply,
+)]
+#[derive(Default)]
 pub struct HelloServer {
     share_states: Cell<i32>,
 }
 
 impl HelloServer {
     pub fn new() -> Self {
-        Self {
-            share_states: Cell::default(),
-        }
+        Self::default()
     }
 
-    async fn hello(&self, request: bytes::Bytes) -> (u32, bytes::Bytes) {
-        println!("read request {:?}", request);
+    async fn hello(&self, request: HelloRequest, ctx: CallContext) -> Result<HelloReply, Status> {
+        println!("read request {:?} from {:?}", request, ctx.peer_addr());
+        let calls = ctx.extensions().with(|calls: &mut u32| {
+            *calls += 1;
+            *calls
+        });
+        println!("call {} on this connection", calls);
         let count = self.share_states.get();
         self.share_states.set(count + 1);
-        (0, format!("{} hello reply", count).into())
+        Ok(HelloReply {
+            msg: format!("{} hello {}", count, request.name),
+            time: format!("{:?}", std::time::SystemTime::now()),
+        })
     }
 
     async fn hello_stream(
@@ -38,6 +55,38 @@ impl HelloServer {
             .await?;
         Ok(())
     }
+
+    async fn hello_bidi(
+        &self,
+        requests: RequestStream<HelloRequest>,
+        replies: ReplySink<HelloReply>,
+    ) -> Result<(), Status> {
+        requests
+            .map(|request| {
+                request.map(|request| HelloReply {
+                    msg: format!("echo {}", request.name),
+                    time: format!("{:?}", std::time::SystemTime::now()),
+                })
+            })
+            .forward(replies)
+            .await
+    }
+}
+
+struct CallLogger;
+
+#[async_trait::async_trait(?Send)]
+impl Interceptor for CallLogger {
+    async fn on_call(&self, ctx: &mut CallContext) -> Result<(), Status> {
+        println!(
+            "call {}.{} request_id={} metadata={:?}",
+            ctx.service_name(),
+            ctx.method_name(),
+            ctx.request_id(),
+            ctx.metadata()
+        );
+        Ok(())
+    }
 }
 
 #[tokio::main]
@@ -51,10 +100,14 @@ async fn main() -> Result<(), ServerError> {
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
     let mut server = Server::new(8080).await?;
+    server.add_interceptor(CallLogger);
     let s1 = HelloServer::new();
     let s2 = HelloServer::new();
     server.register_service(s1);
     server.register_service(s2);
+    let (health, health_service) = health_reporter();
+    health.set_serving("HelloServer");
+    server.register_service(health_service);
     println!("{:?}", server.list_service());
 
     let local = task::LocalSet::new();
@@ -62,11 +115,19 @@ async fn main() -> Result<(), ServerError> {
         .run_until(async move {
             let mut server = server;
             loop {
-                let c = server.accept().await?;
-                let _ = task::spawn_local(async move {
-                    let mut c = c;
-                    c.run().await
-                });
+                tokio::select! {
+                    c = server.accept() => {
+                        let c = c?;
+                        task::spawn_local(async move {
+                            let mut c = c;
+                            c.run().await
+                        });
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        health.shutdown();
+                        break;
+                    }
+                }
             }
             Result::<(), ServerError>::Ok(())
         })
@@ -0,0 +1,674 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures::{
+    future::BoxFuture,
+    stream::{BoxStream, FuturesUnordered},
+    FutureExt, StreamExt,
+};
+use tokio::{
+    sync::{mpsc, Notify},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use super::{
+    breaker::{BreakerConfig, CircuitBreaker, CircuitKey, Trial, TrialObserver},
+    managed::{Backoff, CallPolicy, ConnectivityState, ManagedChannel, RunningManagedChannel},
+    resolve::{parse_target, Resolver},
+    ClientChannel, ClientError, ClientReaderWriter, InterceptorChain, RunningChannel, UnaryCall,
+};
+
+type Picked = (SocketAddr, RunningChannel, Option<Trial>);
+
+// how often a removed endpoint checks for calls still on it
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+/// How `BalancedChannel` picks an endpoint for a call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalancePolicy {
+    // the first ready endpoint in the order they were added
+    PickFirst,
+    RoundRobin,
+    // the less loaded of two random ready endpoints, by in-flight calls
+    PowerOfTwoChoices,
+}
+
+/// A channel to a set of endpoints, one reconnecting connection each.
+///
+/// Endpoints which are not `Ready` are ejected from picking until they
+/// reconnect, so are endpoints whose circuit for the method is open.
+pub struct BalancedChannel {
+    endpoints: Vec<SocketAddr>,
+    // replaces `endpoints` on every resolved set
+    resolved: Option<BoxStream<'static, Vec<SocketAddr>>>,
+    policy: BalancePolicy,
+    backoff: Backoff,
+    call_policy: CallPolicy,
+    breaker: Option<CircuitBreaker>,
+    drain_timeout: Duration,
+}
+
+struct Endpoint {
+    addr: SocketAddr,
+    channel: RunningManagedChannel,
+}
+
+/// Handle of a running `BalancedChannel`.
+#[derive(Clone)]
+pub struct RunningBalancedChannel {
+    endpoints: Arc<Mutex<Vec<Endpoint>>>,
+    policy: BalancePolicy,
+    backoff: Backoff,
+    call_policy: CallPolicy,
+    breaker: Option<CircuitBreaker>,
+    drain_timeout: Duration,
+    next: Arc<AtomicUsize>,
+    // notified on every endpoint state change
+    changed: Arc<Notify>,
+    spawn_tx: mpsc::UnboundedSender<BoxFuture<'static, ()>>,
+    shutdown: CancellationToken,
+}
+
+impl BalancedChannel {
+    pub fn new(endpoints: impl IntoIterator<Item = SocketAddr>) -> Self {
+        Self {
+            endpoints: endpoints.into_iter().collect(),
+            resolved: None,
+            policy: BalancePolicy::RoundRobin,
+            backoff: Backoff::default(),
+            call_policy: CallPolicy::default(),
+            breaker: None,
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Connect to the addresses `resolver` gives for `rspc://name`, adding
+    /// and removing connections as they change.
+    pub fn resolve(target: &str, resolver: &impl Resolver) -> Result<Self, ClientError> {
+        let name = parse_target(target)?;
+        let mut channel = Self::new([]);
+        channel.resolved = Some(resolver.resolve(name));
+        Ok(channel)
+    }
+
+    pub fn policy(mut self, policy: BalancePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Reconnect backoff of every endpoint.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn call_policy(mut self, policy: CallPolicy) -> Self {
+        self.call_policy = policy;
+        self
+    }
+
+    /// Break circuits per endpoint and method with `config`.
+    pub fn circuit_breaker(self, config: BreakerConfig) -> Self {
+        self.with_breaker(CircuitBreaker::new(config))
+    }
+
+    /// Use `breaker`, e.g. one with a state callback.
+    pub fn with_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// How long a removed endpoint waits for its calls before its
+    /// connection is closed.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// The returned future drives every endpoint connection until
+    /// `RunningBalancedChannel::shutdown`.
+    pub fn run(
+        self,
+    ) -> (
+        impl futures::Future<Output = ()> + 'static,
+        RunningBalancedChannel,
+    ) {
+        let (spawn_tx, mut spawn_rx) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let running = RunningBalancedChannel {
+            endpoints: Default::default(),
+            policy: self.policy,
+            backoff: self.backoff,
+            call_policy: self.call_policy,
+            breaker: self.breaker,
+            drain_timeout: self.drain_timeout,
+            next: Default::default(),
+            changed: Default::default(),
+            spawn_tx,
+            shutdown: shutdown.clone(),
+        };
+        for addr in self.endpoints {
+            running.add_endpoint(addr);
+        }
+
+        let handle = running.clone();
+        let mut resolved = self
+            .resolved
+            .unwrap_or_else(|| futures::stream::pending().boxed());
+        let ret = async move {
+            let mut connections = FuturesUnordered::new();
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    Some(connection) = spawn_rx.recv() => connections.push(connection),
+                    Some(()) = connections.next(), if !connections.is_empty() => {}
+                    Some(addrs) = resolved.next() => handle.set_endpoints(addrs),
+                }
+            }
+            let endpoints: Vec<_> = handle.endpoints.lock().unwrap().drain(..).collect();
+            for endpoint in endpoints {
+                endpoint.channel.shutdown();
+            }
+            // draining endpoints stop with `shutdown` too, every connection
+            // ends its calls before the run future returns
+            while connections.next().await.is_some() {}
+        };
+        (ret, running)
+    }
+}
+
+impl RunningBalancedChannel {
+    /// Connect to one more endpoint, a no-op if it is already in the set.
+    pub fn add_endpoint(&self, addr: SocketAddr) {
+        if self
+            .endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| e.addr == addr)
+        {
+            return;
+        }
+        info!(%addr, "add endpoint");
+        let (run, channel) = ManagedChannel::new(addr)
+            .backoff(self.backoff.clone())
+            .call_policy(CallPolicy::FailFast)
+            .run();
+        let mut state = channel.subscribe();
+        let changed = self.changed.clone();
+        let watch = async move {
+            while state.changed().await.is_ok() {
+                changed.notify_waiters();
+            }
+        };
+        let connection = futures::future::join(run, watch).map(|_| ());
+        if self.spawn_tx.send(connection.boxed()).is_ok() {
+            self.endpoints
+                .lock()
+                .unwrap()
+                .push(Endpoint { addr, channel });
+        }
+    }
+
+    /// Stop picking `addr`, its connection is closed once the calls already
+    /// on it end or the drain timeout passes.
+    pub fn remove_endpoint(&self, addr: SocketAddr) {
+        let endpoint = {
+            let mut endpoints = self.endpoints.lock().unwrap();
+            match endpoints.iter().position(|e| e.addr == addr) {
+                Some(i) => endpoints.remove(i),
+                None => return,
+            }
+        };
+        info!(%addr, "remove endpoint");
+        let channel = endpoint.channel;
+        let drain_timeout = self.drain_timeout;
+        let shutdown = self.shutdown.clone();
+        let drain = async move {
+            let drained = async {
+                while channel.in_flight() > 0 {
+                    time::sleep(DRAIN_POLL).await;
+                }
+            };
+            tokio::select! {
+                _ = time::timeout(drain_timeout, drained) => {}
+                _ = shutdown.cancelled() => {}
+            }
+            info!(%addr, in_flight = channel.in_flight(), "endpoint drained");
+            channel.shutdown();
+        };
+        if self.spawn_tx.send(drain.boxed()).is_err() {
+            // the run future is gone, nothing drives the connection
+            info!(%addr, "balanced channel stopped, endpoint not drained");
+        }
+    }
+
+    /// Add the endpoints not in the set and remove the ones not in `addrs`.
+    pub fn set_endpoints(&self, addrs: Vec<SocketAddr>) {
+        let removed: Vec<_> = {
+            let endpoints = self.endpoints.lock().unwrap();
+            endpoints
+                .iter()
+                .map(|e| e.addr)
+                .filter(|addr| !addrs.contains(addr))
+                .collect()
+        };
+        for addr in removed {
+            self.remove_endpoint(addr);
+        }
+        for addr in addrs {
+            self.add_endpoint(addr);
+        }
+    }
+
+    pub fn endpoints(&self) -> Vec<(SocketAddr, ConnectivityState)> {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints
+            .iter()
+            .map(|e| (e.addr, e.channel.state()))
+            .collect()
+    }
+
+    /// Close every connection, the run future returns.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// The circuit breaker, if any.
+    pub fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_ref()
+    }
+
+    /// An endpoint for a call to `method_id`, with the breaker trial the
+    /// call was admitted with.
+    fn pick(&self, method_id: u32) -> Result<Picked, ClientError> {
+        let mut ready: Vec<_> = {
+            let endpoints = self.endpoints.lock().unwrap();
+            endpoints
+                .iter()
+                .filter_map(|e| e.channel.connected().map(|c| (e.addr, c)))
+                .collect()
+        };
+        if ready.is_empty() {
+            return Err(ClientError::Unavailable());
+        }
+        let key = |endpoint| CircuitKey {
+            endpoint,
+            method_id,
+        };
+        if let Some(breaker) = &self.breaker {
+            ready.retain(|(endpoint, _)| breaker.available(key(*endpoint)));
+        }
+        loop {
+            let i = match (self.policy, ready.len()) {
+                (_, 0) => return Err(ClientError::CircuitOpen()),
+                (BalancePolicy::PickFirst, _) | (_, 1) => 0,
+                (BalancePolicy::RoundRobin, n) => {
+                    let next = self.next.fetch_add(1, Ordering::Relaxed);
+                    next % n
+                }
+                (BalancePolicy::PowerOfTwoChoices, n) => {
+                    let a = fastrand::usize(..n);
+                    let b = (a + fastrand::usize(1..n)) % n;
+                    if ready[a].1.in_flight() <= ready[b].1.in_flight() {
+                        a
+                    } else {
+                        b
+                    }
+                }
+            };
+            let (endpoint, channel) = ready.swap_remove(i);
+            let Some(breaker) = &self.breaker else {
+                return Ok((endpoint, channel, None));
+            };
+            // another call may have taken the last trial slot since `available`
+            if let Some(trial) = breaker.try_acquire(key(endpoint)) {
+                return Ok((endpoint, channel, Some(trial)));
+            }
+        }
+    }
+
+    async fn wait_pick(&self, method_id: u32) -> Result<Picked, ClientError> {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            match self.pick(method_id) {
+                Err(ClientError::Unavailable()) => {}
+                picked => return picked,
+            }
+            if self.shutdown.is_cancelled() {
+                return Err(ClientError::ConnectionLost());
+            }
+            changed.await;
+        }
+    }
+
+    async fn pick_by_policy(&self, method_id: u32) -> Result<Picked, ClientError> {
+        match self.call_policy {
+            CallPolicy::FailFast => self.pick(method_id),
+            CallPolicy::Queue { timeout } => time::timeout(timeout, self.wait_pick(method_id))
+                .await
+                .map_err(|_| ClientError::Unavailable())?,
+        }
+    }
+}
+
+#[async_trait]
+impl ClientChannel for RunningBalancedChannel {
+    async fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError> {
+        // streams pass the breaker but give their trial back and are not
+        // counted
+        let (_, channel, _) = self.pick_by_policy(method_id).await?;
+        channel.call_method(method_id)
+    }
+
+    async fn start_unary(&self, call: &UnaryCall) -> Result<ClientReaderWriter, ClientError> {
+        let (_, channel, trial) = self.pick_by_policy(call.method_id).await?;
+        let start = Instant::now();
+        let sent = match channel.call_method(call.method_id) {
+            Ok(mut rw) => call.write(&mut rw).await.map(|_| rw),
+            Err(e) => Err(e),
+        };
+        match (sent, trial) {
+            // the trial ends with the reply, or goes back if the call is dropped
+            (Ok(mut rw), Some(trial)) => {
+                let observer = TrialObserver::new(trial, start);
+                rw.intercept(&InterceptorChain::new().with(observer));
+                Ok(rw)
+            }
+            (Err(e), Some(trial)) => {
+                trial.record_error(start.elapsed());
+                Err(e)
+            }
+            (sent, None) => sent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+    use bytes::Bytes;
+    use tokio::{net::TcpListener, task};
+    use tower::{service_fn, BoxError};
+
+    use super::*;
+    use crate::{
+        client::{BreakerState, HedgePolicy, RetryChannel, RetryPolicy},
+        protocol::{Code, Status},
+        server::{self, service::ServiceTable, TowerRequest, TowerService},
+    };
+
+    /// An endpoint which accepts connections and never replies.
+    async fn listen() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.push(stream);
+            }
+        });
+        addr
+    }
+
+    /// An address nothing listens on.
+    async fn refused() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn start(channel: BalancedChannel) -> RunningBalancedChannel {
+        let (run, running) = channel.run();
+        tokio::spawn(run);
+        running
+    }
+
+    async fn wait_ready(channel: &RunningBalancedChannel, addrs: &[SocketAddr]) {
+        let ready = async {
+            loop {
+                let endpoints = channel.endpoints();
+                if addrs
+                    .iter()
+                    .all(|addr| endpoints.contains(&(*addr, ConnectivityState::Ready)))
+                {
+                    return;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(5), ready).await.unwrap();
+    }
+
+    /// Serve `service` on the `LocalSet` of the test, the server is `!Send`.
+    async fn serve<S>(service: S) -> SocketAddr
+    where
+        S: tower::Service<TowerRequest, Response = (u32, Bytes)> + Send + 'static,
+        S::Future: Send,
+        S::Error: Into<BoxError> + Send + Sync,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut table = ServiceTable::new();
+        table.register_service(TowerService::new("Test", &["test"], service));
+        task::spawn_local(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut channel = server::Channel::new(stream, Rc::new(RefCell::new(table)));
+            let _ = channel.run().await;
+        });
+        addr
+    }
+
+    fn picks(channel: &RunningBalancedChannel, n: usize) -> Vec<SocketAddr> {
+        (0..n).map(|_| channel.pick(0).unwrap().0).collect()
+    }
+
+    #[tokio::test]
+    async fn pick_first_and_round_robin() {
+        let addrs = [listen().await, listen().await, listen().await];
+
+        let channel = start(BalancedChannel::new(addrs).policy(BalancePolicy::PickFirst));
+        wait_ready(&channel, &addrs).await;
+        assert!(picks(&channel, 6).iter().all(|addr| *addr == addrs[0]));
+        channel.shutdown();
+
+        let channel = start(BalancedChannel::new(addrs).policy(BalancePolicy::RoundRobin));
+        wait_ready(&channel, &addrs).await;
+        let picked = picks(&channel, 6);
+        assert_eq!(picked[..3], picked[3..]);
+        for addr in addrs {
+            assert!(picked[..3].contains(&addr));
+        }
+        channel.shutdown();
+    }
+
+    #[tokio::test]
+    async fn p2c_picks_less_loaded() {
+        let addrs = [listen().await, listen().await];
+        let channel = start(BalancedChannel::new(addrs).policy(BalancePolicy::PowerOfTwoChoices));
+        wait_ready(&channel, &addrs).await;
+
+        let (loaded, running, _) = channel.pick(0).unwrap();
+        let _call = running.call_method(0).unwrap();
+        assert!(picks(&channel, 10).iter().all(|addr| *addr != loaded));
+        channel.shutdown();
+    }
+
+    #[tokio::test]
+    async fn not_ready_endpoints_are_ejected() {
+        let live = listen().await;
+        let dead = refused().await;
+        let channel = start(BalancedChannel::new([dead, live]).policy(BalancePolicy::PickFirst));
+        wait_ready(&channel, &[live]).await;
+
+        assert!(picks(&channel, 6).iter().all(|addr| *addr == live));
+        channel.shutdown();
+    }
+
+    #[tokio::test]
+    async fn set_endpoints_adds_and_removes() {
+        let [a, b, c] = [listen().await, listen().await, listen().await];
+        let channel = start(BalancedChannel::new([a, b]));
+        wait_ready(&channel, &[a, b]).await;
+
+        channel.set_endpoints(vec![b, c]);
+        let addrs: Vec<_> = channel
+            .endpoints()
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .collect();
+        assert_eq!(addrs, [b, c]);
+        wait_ready(&channel, &[b, c]).await;
+        let picked = picks(&channel, 4);
+        assert!(picked.contains(&b) && picked.contains(&c));
+        assert!(!picked.contains(&a));
+        channel.shutdown();
+    }
+
+    #[tokio::test]
+    async fn open_circuits_are_skipped() {
+        let addrs = [listen().await, listen().await];
+        let config = BreakerConfig {
+            window: 1,
+            min_calls: 1,
+            open_for: Duration::from_secs(60),
+            ..BreakerConfig::default()
+        };
+        let channel = start(
+            BalancedChannel::new(addrs)
+                .policy(BalancePolicy::RoundRobin)
+                .circuit_breaker(config),
+        );
+        wait_ready(&channel, &addrs).await;
+
+        let breaker = channel.breaker().unwrap();
+        let key = CircuitKey {
+            endpoint: addrs[0],
+            method_id: 0,
+        };
+        breaker.record(key, &Err(ClientError::Unavailable()), Duration::ZERO);
+        assert_eq!(breaker.state(key), BreakerState::Open);
+
+        let mut counts = HashMap::new();
+        for addr in picks(&channel, 4) {
+            *counts.entry(addr).or_insert(0) += 1;
+        }
+        assert_eq!(counts, HashMap::from([(addrs[1], 4)]));
+
+        // other methods of the endpoint are still picked
+        assert!((0..4).any(|_| channel.pick(1).unwrap().0 == addrs[0]));
+
+        // every circuit open
+        let key = CircuitKey {
+            endpoint: addrs[1],
+            method_id: 0,
+        };
+        breaker.record(key, &Err(ClientError::Unavailable()), Duration::ZERO);
+        assert!(matches!(channel.pick(0), Err(ClientError::CircuitOpen())));
+        channel.shutdown();
+    }
+
+    #[tokio::test]
+    async fn half_open_admits_one_pick() {
+        let addr = listen().await;
+        let config = BreakerConfig {
+            window: 1,
+            min_calls: 1,
+            open_for: Duration::ZERO,
+            half_open_calls: 1,
+            ..BreakerConfig::default()
+        };
+        let channel = start(BalancedChannel::new([addr]).circuit_breaker(config));
+        wait_ready(&channel, &[addr]).await;
+
+        let key = CircuitKey {
+            endpoint: addr,
+            method_id: 0,
+        };
+        let breaker = channel.breaker().unwrap();
+        breaker.record(key, &Err(ClientError::Unavailable()), Duration::ZERO);
+
+        let (_, _, trial) = channel.pick(0).unwrap();
+        assert!(trial.is_some());
+        assert_eq!(breaker.state(key), BreakerState::HalfOpen);
+        assert!(matches!(channel.pick(0), Err(ClientError::CircuitOpen())));
+        drop(trial);
+        assert!(channel.pick(0).is_ok());
+        channel.shutdown();
+    }
+
+    #[tokio::test]
+    async fn hedged_attempts_count_toward_circuit() {
+        task::LocalSet::new()
+            .run_until(async {
+                let addr = serve(service_fn(|_: TowerRequest| async {
+                    Err::<(u32, Bytes), _>(BoxError::from(Status::unavailable("down")))
+                }))
+                .await;
+
+                let config = BreakerConfig {
+                    window: 2,
+                    min_calls: 2,
+                    open_for: Duration::from_secs(60),
+                    ..BreakerConfig::default()
+                };
+                let channel = start(BalancedChannel::new([addr]).circuit_breaker(config));
+                wait_ready(&channel, &[addr]).await;
+                let hedge = HedgePolicy {
+                    max_attempts: 2,
+                    delay: Duration::from_millis(1),
+                    ..HedgePolicy::default()
+                };
+                let retry = RetryChannel::new(channel.clone(), RetryPolicy::default()).hedge(hedge);
+
+                let mut call = UnaryCall::new(0, Bytes::new());
+                call.idempotent = true;
+                let (status_code, _) = retry.unary(&call).await.unwrap();
+                assert_eq!(Code::from(status_code), Code::Unavailable);
+
+                let key = CircuitKey {
+                    endpoint: addr,
+                    method_id: 0,
+                };
+                assert_eq!(channel.breaker().unwrap().state(key), BreakerState::Open);
+                channel.shutdown();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn removed_endpoint_finishes_its_calls() {
+        task::LocalSet::new()
+            .run_until(async {
+                let addr = serve(service_fn(|request: TowerRequest| async move {
+                    time::sleep(Duration::from_millis(100)).await;
+                    Ok::<_, BoxError>((0, request.body))
+                }))
+                .await;
+                let channel = start(BalancedChannel::new([addr]));
+                wait_ready(&channel, &[addr]).await;
+
+                let call = UnaryCall::new(0, Bytes::from_static(b"slow"));
+                let remove = async {
+                    time::sleep(Duration::from_millis(20)).await;
+                    channel.remove_endpoint(addr);
+                    assert!(channel.endpoints().is_empty());
+                };
+                let (reply, ()) = tokio::join!(channel.unary(&call), remove);
+                assert_eq!(reply.unwrap(), (0, Bytes::from_static(b"slow")));
+                channel.shutdown();
+            })
+            .await;
+    }
+}
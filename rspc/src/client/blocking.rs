@@ -0,0 +1,242 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use bytes::Bytes;
+use futures::{stream, FutureExt, Stream, StreamExt};
+use tokio::{
+    net::ToSocketAddrs,
+    runtime,
+    sync::{mpsc, oneshot},
+};
+use tracing::info;
+
+use crate::codec::{Codec, ProstCodec};
+
+use super::{Channel, ClientChannel, ClientError, ReplyStream, SharedChannel, UnaryCall};
+
+/// A client channel for code without an async runtime.
+///
+/// The channel is driven by a runtime on a background thread, calls block
+/// the calling thread. Clones share the thread, it stops with the last one.
+#[derive(Clone)]
+pub struct BlockingChannel {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    channel: SharedChannel,
+    handle: runtime::Handle,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BlockingChannel {
+    /// Connect to `addr` with a single `Channel`.
+    pub fn connect<A>(addr: A) -> Result<Self, ClientError>
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        Self::spawn(|| async move {
+            let (run, channel) = Channel::new(addr).await?.run();
+            let run = run.map(|r| info!(result = ?r, "blocking channel closed"));
+            Ok((run, channel))
+        })
+    }
+
+    /// Run `setup` on the background runtime. The future it returns drives
+    /// the channel until the `BlockingChannel` is dropped, e.g.
+    /// `|| async { Ok(ManagedChannel::new(addr).run()) }`.
+    pub fn spawn<S, F, R, C>(setup: S) -> Result<Self, ClientError>
+    where
+        S: FnOnce() -> F + Send + 'static,
+        F: Future<Output = Result<(R, C), ClientError>>,
+        R: Future,
+        C: ClientChannel + 'static,
+    {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("rspc-blocking".into())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let (run, channel) = match setup().await {
+                        Ok(ok) => ok,
+                        Err(e) => {
+                            let _ = ready_tx.send(Err(e));
+                            return;
+                        }
+                    };
+                    let channel: SharedChannel = Arc::new(channel);
+                    if ready_tx.send(Ok(channel)).is_err() {
+                        return;
+                    }
+                    tokio::select! {
+                        _ = run => {}
+                        _ = shutdown_rx => {}
+                    }
+                })
+            })?;
+        let channel = ready_rx
+            .blocking_recv()
+            .map_err(|_| ClientError::ConnectionLost())??;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                channel,
+                handle,
+                shutdown: Some(shutdown),
+                thread: Some(thread),
+            }),
+        })
+    }
+
+    /// The channel on the background runtime, for async stubs.
+    pub fn channel(&self) -> &SharedChannel {
+        &self.inner.channel
+    }
+
+    pub fn handle(&self) -> &runtime::Handle {
+        &self.inner.handle
+    }
+
+    /// Block on `f` within the background runtime's context. Must not be
+    /// called from async code.
+    pub fn block_on<F: Future>(&self, f: F) -> F::Output {
+        let _guard = self.inner.handle.enter();
+        futures::executor::block_on(f)
+    }
+
+    pub fn unary(&self, call: &UnaryCall) -> Result<(u32, Bytes), ClientError> {
+        self.block_on(self.inner.channel.unary(call))
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            // dropped by a task of the background runtime itself
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Decoded replies of a `server_stream` or `bidi` call, read by blocking
+/// on each one.
+pub struct BlockingStream<T, C = ProstCodec> {
+    stream: ReplyStream<T, C>,
+    channel: BlockingChannel,
+}
+
+impl<T, C: Codec<T>> BlockingStream<T, C> {
+    pub fn new(stream: ReplyStream<T, C>, channel: BlockingChannel) -> Self {
+        Self { stream, channel }
+    }
+}
+
+impl<T, C: Codec<T>> Iterator for BlockingStream<T, C> {
+    type Item = Result<T, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.channel.block_on(self.stream.next())
+    }
+}
+
+/// The items of `requests` as a stream, pulled on a thread of their own so
+/// a blocking bidi call sends each one as it is yielded while the caller
+/// reads replies.
+///
+/// The thread stops at the end of `requests` or at the first item after the
+/// call ended.
+pub fn request_stream<I>(requests: I) -> impl Stream<Item = I::Item> + Send + 'static
+where
+    I: IntoIterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    let (request_tx, mut request_rx) = mpsc::channel(1);
+    thread::Builder::new()
+        .name("rspc-requests".into())
+        .spawn(move || {
+            for request in requests {
+                if request_tx.blocking_send(request).is_err() {
+                    break;
+                }
+            }
+        })
+        .expect("spawn request thread");
+    stream::poll_fn(move |cx| request_rx.poll_recv(cx))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, net::SocketAddr, rc::Rc, sync::mpsc};
+
+    use tokio::{net::TcpListener, task::LocalSet};
+
+    use super::*;
+    use crate::{
+        example::{pb, GreeterBlockingClient, GreeterServer, HelloServer},
+        server::{self, service::ServiceTable},
+    };
+
+    /// Serve one connection on a thread of its own, the server is `!Send`.
+    fn serve_one() -> SocketAddr {
+        let (addr_tx, addr_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            LocalSet::new().block_on(&runtime, async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                addr_tx.send(listener.local_addr().unwrap()).unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut table = ServiceTable::new();
+                table.register_service(GreeterServer::new(HelloServer::new()));
+                let mut channel = server::Channel::new(stream, Rc::new(RefCell::new(table)));
+                let _ = channel.run().await;
+            });
+        });
+        addr_rx.recv().unwrap()
+    }
+
+    #[test]
+    fn unary_round_trip() {
+        let channel = BlockingChannel::connect(serve_one()).unwrap();
+        let client = GreeterBlockingClient::new(channel, 0);
+        for n in 0..2 {
+            let request = pb::HelloRequest {
+                name: "blocking".into(),
+            };
+            let reply = client.hello(request).unwrap();
+            assert_eq!(reply.msg, format!("{} hello blocking", n));
+        }
+    }
+
+    #[test]
+    fn bidi_streams_requests() {
+        let channel = BlockingChannel::connect(serve_one()).unwrap();
+        let client = GreeterBlockingClient::new(channel, 0);
+        // each request is only yielded after the reply to the one before
+        let (request_tx, request_rx) = mpsc::channel();
+        let request = |name: &str| pb::HelloRequest { name: name.into() };
+        request_tx.send(request("a")).unwrap();
+        let mut replies = client.hello_stream(request_rx).unwrap();
+
+        assert_eq!(replies.next().unwrap().unwrap().msg, "echo a");
+        request_tx.send(request("b")).unwrap();
+        assert_eq!(replies.next().unwrap().unwrap().msg, "echo b");
+        drop(request_tx);
+        assert!(replies.next().is_none());
+    }
+}
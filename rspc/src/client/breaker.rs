@@ -0,0 +1,362 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tracing::info;
+
+use crate::protocol::Code;
+
+use super::{ClientError, Interceptor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    // calls go through, results are counted
+    Closed,
+    // calls fail fast with `ClientError::CircuitOpen`
+    Open,
+    // a few trial calls decide between closed and open
+    HalfOpen,
+}
+
+/// One circuit per endpoint and method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CircuitKey {
+    pub endpoint: SocketAddr,
+    pub method_id: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    // the last `window` calls are counted
+    pub window: usize,
+    // no opening before this many calls are counted
+    pub min_calls: usize,
+    pub failure_rate: f64,
+    // a call slower than this counts as failed
+    pub slow_call: Option<Duration>,
+    // status codes counted as failures, transport errors always are
+    pub failure_codes: Vec<Code>,
+    // how long an open circuit waits before half-open
+    pub open_for: Duration,
+    // trial calls while half-open, all must succeed to close
+    pub half_open_calls: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            min_calls: 10,
+            failure_rate: 0.5,
+            slow_call: None,
+            failure_codes: vec![
+                Code::Unknown,
+                Code::DeadlineExceeded,
+                Code::Internal,
+                Code::Unavailable,
+            ],
+            open_for: Duration::from_secs(30),
+            half_open_calls: 1,
+        }
+    }
+}
+
+pub type StateCallback = Arc<dyn Fn(CircuitKey, BreakerState) + Send + Sync>;
+
+struct Circuit {
+    state: BreakerState,
+    // true for a failed call
+    results: VecDeque<bool>,
+    opened_at: Instant,
+    trials: u32,
+    passed: u32,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        Self {
+            state: BreakerState::Closed,
+            results: VecDeque::new(),
+            opened_at: Instant::now(),
+            trials: 0,
+            passed: 0,
+        }
+    }
+}
+
+/// Circuit breakers of the endpoints and methods of a channel.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    config: Arc<BreakerConfig>,
+    circuits: Arc<Mutex<HashMap<CircuitKey, Circuit>>>,
+    on_change: Option<StateCallback>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            circuits: Default::default(),
+            on_change: None,
+        }
+    }
+
+    /// Called on every state change.
+    pub fn on_state_change(mut self, callback: StateCallback) -> Self {
+        self.on_change = Some(callback);
+        self
+    }
+
+    pub fn state(&self, key: CircuitKey) -> BreakerState {
+        let circuits = self.circuits.lock().unwrap();
+        circuits.get(&key).map_or(BreakerState::Closed, |c| c.state)
+    }
+
+    /// Whether `try_acquire` would admit a call to `key` now, a hint which
+    /// takes no slot.
+    pub fn available(&self, key: CircuitKey) -> bool {
+        let circuits = self.circuits.lock().unwrap();
+        circuits
+            .get(&key)
+            .is_none_or(|circuit| match circuit.state {
+                BreakerState::Closed => true,
+                BreakerState::Open => circuit.opened_at.elapsed() >= self.config.open_for,
+                BreakerState::HalfOpen => circuit.trials < self.config.half_open_calls,
+            })
+    }
+
+    /// Admit a call to `key`, `None` while the circuit is open or all its
+    /// half-open trial slots are taken. An open circuit past `open_for`
+    /// turns half-open.
+    pub fn try_acquire(&self, key: CircuitKey) -> Option<Trial> {
+        let (admitted, half_opened) = {
+            let mut circuits = self.circuits.lock().unwrap();
+            let circuit = circuits.entry(key).or_insert_with(Circuit::new);
+            let half_opened = circuit.state == BreakerState::Open
+                && circuit.opened_at.elapsed() >= self.config.open_for;
+            if half_opened {
+                circuit.state = BreakerState::HalfOpen;
+                circuit.trials = 0;
+                circuit.passed = 0;
+            }
+            // the half-open period a trial slot is taken in
+            let admitted = match circuit.state {
+                BreakerState::Closed => Some(None),
+                BreakerState::Open => None,
+                BreakerState::HalfOpen if circuit.trials < self.config.half_open_calls => {
+                    circuit.trials += 1;
+                    Some(Some(circuit.opened_at))
+                }
+                BreakerState::HalfOpen => None,
+            };
+            (admitted, half_opened)
+        };
+        if half_opened {
+            self.notify(key, BreakerState::HalfOpen);
+        }
+        admitted.map(|slot| Trial {
+            breaker: self.clone(),
+            key,
+            slot,
+        })
+    }
+
+    /// Count the result of a unary call to `key` that took `latency`.
+    pub fn record(
+        &self,
+        key: CircuitKey,
+        result: &Result<(u32, Bytes), ClientError>,
+        latency: Duration,
+    ) {
+        let failed = match result {
+            Ok((status_code, _)) => self.is_failure(*status_code),
+            Err(_) => true,
+        };
+        self.count_result(key, failed, latency);
+    }
+
+    fn is_failure(&self, status_code: u32) -> bool {
+        status_code != 0 && self.config.failure_codes.contains(&Code::from(status_code))
+    }
+
+    fn count_result(&self, key: CircuitKey, failed: bool, latency: Duration) {
+        let failed = failed || self.config.slow_call.is_some_and(|slow| latency > slow);
+        let changed = {
+            let mut circuits = self.circuits.lock().unwrap();
+            let circuit = circuits.entry(key).or_insert_with(Circuit::new);
+            self.count(circuit, failed)
+        };
+        if let Some(state) = changed {
+            self.notify(key, state);
+        }
+    }
+
+    /// Give back a trial slot of the half-open period started at `opened_at`.
+    fn release(&self, key: CircuitKey, opened_at: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(&key) {
+            if circuit.state == BreakerState::HalfOpen && circuit.opened_at == opened_at {
+                circuit.trials = circuit.trials.saturating_sub(1);
+            }
+        }
+    }
+
+    fn notify(&self, key: CircuitKey, state: BreakerState) {
+        info!(endpoint = %key.endpoint, method_id = key.method_id, ?state, "circuit state change");
+        if let Some(on_change) = &self.on_change {
+            on_change(key, state);
+        }
+    }
+
+    /// Update `circuit` with one result, the new state if it changed.
+    fn count(&self, circuit: &mut Circuit, failed: bool) -> Option<BreakerState> {
+        let config = &self.config;
+        match circuit.state {
+            BreakerState::Closed => {
+                circuit.results.push_back(failed);
+                while circuit.results.len() > config.window {
+                    circuit.results.pop_front();
+                }
+                let failures = circuit.results.iter().filter(|f| **f).count();
+                let calls = circuit.results.len();
+                if calls >= config.min_calls
+                    && failures as f64 >= config.failure_rate * calls as f64
+                {
+                    circuit.state = BreakerState::Open;
+                    circuit.opened_at = Instant::now();
+                    return Some(BreakerState::Open);
+                }
+                None
+            }
+            BreakerState::HalfOpen if failed => {
+                circuit.state = BreakerState::Open;
+                circuit.opened_at = Instant::now();
+                Some(BreakerState::Open)
+            }
+            BreakerState::HalfOpen => {
+                circuit.passed += 1;
+                if circuit.passed >= config.half_open_calls {
+                    circuit.state = BreakerState::Closed;
+                    circuit.results.clear();
+                    return Some(BreakerState::Closed);
+                }
+                None
+            }
+            // a call started before the circuit opened
+            BreakerState::Open => None,
+        }
+    }
+}
+
+/// A call admitted by `CircuitBreaker::try_acquire`.
+///
+/// A trial dropped without `record`, e.g. by a caller timeout, gives its
+/// half-open slot back.
+#[must_use]
+pub struct Trial {
+    breaker: CircuitBreaker,
+    key: CircuitKey,
+    // the half-open period the slot was taken in
+    slot: Option<Instant>,
+}
+
+impl Trial {
+    pub fn record(self, result: &Result<(u32, Bytes), ClientError>, latency: Duration) {
+        let failed = match result {
+            Ok((status_code, _)) => self.breaker.is_failure(*status_code),
+            Err(_) => true,
+        };
+        self.finish(failed, latency);
+    }
+
+    pub(crate) fn record_error(self, latency: Duration) {
+        self.finish(true, latency);
+    }
+
+    fn finish(mut self, failed: bool, latency: Duration) {
+        self.slot = None;
+        self.breaker.count_result(self.key, failed, latency);
+    }
+}
+
+impl Drop for Trial {
+    fn drop(&mut self) {
+        if let Some(opened_at) = self.slot.take() {
+            self.breaker.release(self.key, opened_at);
+        }
+    }
+}
+
+/// Records a trial with the first reply or the error of the call it
+/// observes, a call dropped before either gives the trial back.
+pub(crate) struct TrialObserver {
+    trial: Mutex<Option<Trial>>,
+    start: Instant,
+}
+
+impl TrialObserver {
+    pub(crate) fn new(trial: Trial, start: Instant) -> Self {
+        Self {
+            trial: Mutex::new(Some(trial)),
+            start,
+        }
+    }
+}
+
+impl Interceptor for TrialObserver {
+    fn on_reply(&self, _method_id: u32, status_code: u32, _body: &Bytes) {
+        if let Some(trial) = self.trial.lock().unwrap().take() {
+            let failed = trial.breaker.is_failure(status_code);
+            trial.finish(failed, self.start.elapsed());
+        }
+    }
+
+    fn on_error(&self, _method_id: u32, _error: &ClientError) {
+        if let Some(trial) = self.trial.lock().unwrap().take() {
+            trial.record_error(self.start.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_half_open_close() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            window: 4,
+            min_calls: 4,
+            open_for: Duration::ZERO,
+            ..Default::default()
+        });
+        let key = CircuitKey {
+            endpoint: "127.0.0.1:8080".parse().unwrap(),
+            method_id: 0,
+        };
+        let ok = Ok((0, Bytes::new()));
+        let unavailable = Ok((Code::Unavailable as u32, Bytes::new()));
+        let latency = Duration::from_millis(1);
+
+        breaker.record(key, &ok, latency);
+        for _ in 0..3 {
+            breaker.record(key, &unavailable, latency);
+        }
+        assert_eq!(breaker.state(key), BreakerState::Open);
+
+        assert!(breaker.available(key));
+        let trial = breaker.try_acquire(key).unwrap();
+        assert_eq!(breaker.state(key), BreakerState::HalfOpen);
+        assert!(!breaker.available(key));
+        assert!(breaker.try_acquire(key).is_none());
+        // an abandoned trial frees its slot
+        drop(trial);
+        let trial = breaker.try_acquire(key).unwrap();
+        trial.record(&ok, latency);
+        assert_eq!(breaker.state(key), BreakerState::Closed);
+    }
+}
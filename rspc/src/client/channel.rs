@@ -1,9 +1,13 @@
 use std::{
-    cell::{Cell, RefCell},
-    collections::HashMap,
-    rc::Rc,
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
 };
 
+use async_trait::async_trait;
+use bytes::Bytes;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::{
@@ -12,35 +16,163 @@ use tokio::{
     },
     sync::mpsc,
 };
-use tracing::debug;
+use tracing::{debug, info};
 
-use crate::protocol::frame::*;
+use crate::protocol::{frame::*, StreamError};
 
-use super::{ClientError, ClientReaderWriter};
+use super::{ClientError, ClientReaderWriter, UnaryCall, UnaryService};
 
 const CHANNEL_REPLY_BUF_SIZE: usize = 32;
 const CHANNEL_REQUEST_BUF_SIZE: usize = 32;
 const CHANNEL_SERVICE_BUF_SIZE: usize = 8;
 
+pub(crate) type ReplyResult = Result<ReplyFrame, ClientError>;
+
+type WorkingCalls = Arc<Mutex<HashMap<u32, mpsc::Sender<ReplyResult>>>>;
+
+type OpenRequests = Arc<Mutex<HashSet<u32>>>;
+
+/// Keeps a request id taken while the request half of its call is open,
+/// the server may end the reply half first.
+pub(crate) struct OpenRequest {
+    request_id: u32,
+    open: OpenRequests,
+    working: WorkingCalls,
+}
+
+impl OpenRequest {
+    /// The call was dropped before sending anything, no reply will come.
+    pub(crate) fn abandon(self) {
+        self.working.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+impl Drop for OpenRequest {
+    fn drop(&mut self) {
+        self.open.lock().unwrap().remove(&self.request_id);
+    }
+}
+
 pub struct Channel {
     tcp: TcpStream,
 }
 
+/// Handle of a running `Channel`, cheap to clone and shared across tasks
+/// and threads.
+#[derive(Clone)]
 pub struct RunningChannel {
-    working: Rc<RefCell<HashMap<u32, mpsc::Sender<ReplyFrame>>>>,
+    working: WorkingCalls,
+    open: OpenRequests,
     request_tx: mpsc::Sender<RequestFrame>,
-    next_request_id: Cell<u32>,
+    next_request_id: Arc<AtomicU32>,
+    closed: Arc<AtomicBool>,
 }
 
 impl RunningChannel {
-    pub fn call_method(&self, method_id: u32) -> ClientReaderWriter {
+    pub fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError> {
         let writer_chan = self.request_tx.clone();
         let (service_tx, reader_chan) = mpsc::channel(CHANNEL_SERVICE_BUF_SIZE);
-        let request_id = self.next_request_id.get();
-        self.next_request_id.set(request_id + 1);
-        self.working.borrow_mut().insert(request_id, service_tx);
+        let request_id = {
+            let mut working = self.working.lock().unwrap();
+            // checked under the lock, a call is either failed by `close` or
+            // sees it
+            if self.is_closed() {
+                return Err(ClientError::ConnectionLost());
+            }
+            let mut open = self.open.lock().unwrap();
+            let request_id = self.free_request_id(&working, &open)?;
+            working.insert(request_id, service_tx);
+            open.insert(request_id);
+            request_id
+        };
+
+        let mut rw = ClientReaderWriter::new(writer_chan, reader_chan, request_id, method_id);
+        rw.hold_request_id(OpenRequest {
+            request_id,
+            open: self.open.clone(),
+            working: self.working.clone(),
+        });
+        Ok(rw)
+    }
+
+    /// The next id no call in flight uses, wrapping before the ids kept for
+    /// control frames. Called with the `working` and `open` locks held.
+    fn free_request_id(
+        &self,
+        working: &HashMap<u32, mpsc::Sender<ReplyResult>>,
+        open: &HashSet<u32>,
+    ) -> Result<u32, ClientError> {
+        // most ids are in both, count them once
+        let max = CONTROL_REQUEST_ID_MIN as usize;
+        if working.len() + open.len() >= max {
+            let only_open = open.iter().filter(|id| !working.contains_key(id)).count();
+            if working.len() + only_open >= max {
+                return Err(ClientError::NoRequestId());
+            }
+        }
+        loop {
+            let mut request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+            if is_control_request_id(request_id) {
+                self.next_request_id.store(1, Ordering::Relaxed);
+                request_id = 0;
+            }
+            if !working.contains_key(&request_id) && !open.contains(&request_id) {
+                return Ok(request_id);
+            }
+        }
+    }
+
+    /// Fail every pending call with `ConnectionLost` and refuse new ones, for
+    /// an owner dropping the future returned by `Channel::run`.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        Channel::fail_working(&self.working);
+    }
+
+    /// The future returned by `Channel::run` finished, no call can succeed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire) || self.request_tx.is_closed()
+    }
+
+    /// Calls still waiting for their last reply.
+    pub fn in_flight(&self) -> usize {
+        self.working.lock().unwrap().len()
+    }
+
+    pub fn unary_service(&self, method_id: u32) -> UnaryService {
+        UnaryService::new(self.clone(), method_id)
+    }
+}
+
+/// Where stubs open their calls, a single connection or a channel managing
+/// connections for it.
+#[async_trait]
+pub trait ClientChannel: Send + Sync {
+    async fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError>;
 
-        ClientReaderWriter::new(writer_chan, reader_chan, request_id, method_id)
+    /// Open one attempt of a unary call and send `call` on it, the caller
+    /// reads the reply. Channels which count results, e.g. for a circuit
+    /// breaker, override this.
+    async fn start_unary(&self, call: &UnaryCall) -> Result<ClientReaderWriter, ClientError> {
+        let mut rw = self.call_method(call.method_id).await?;
+        call.write(&mut rw).await?;
+        Ok(rw)
+    }
+
+    /// Run a whole unary call, channels which send it more than once
+    /// override this.
+    async fn unary(&self, call: &UnaryCall) -> Result<(u32, Bytes), ClientError> {
+        self.start_unary(call).await?.read_unary().await
+    }
+}
+
+/// A channel owned by stubs, cloned into each of them.
+pub type SharedChannel = Arc<dyn ClientChannel>;
+
+#[async_trait]
+impl ClientChannel for RunningChannel {
+    async fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError> {
+        RunningChannel::call_method(self, method_id)
     }
 }
 
@@ -66,24 +198,46 @@ impl Channel {
         let (request_tx, request_rx) = mpsc::channel(CHANNEL_REQUEST_BUF_SIZE);
         let (reply_tx, reply_rx) = mpsc::channel(CHANNEL_REPLY_BUF_SIZE);
 
-        let working = Rc::new(RefCell::new(HashMap::default()));
+        let working = WorkingCalls::default();
+        let closed = Arc::new(AtomicBool::new(false));
 
         let writer = Self::channel_writer(tcp_writer, request_rx);
         let reader = Self::channel_reader(tcp_reader, reply_tx);
-        let reply_handler = Self::reply_handler(reply_rx, working.clone());
+        let reply_handler = Self::reply_handler(reply_rx, working.clone(), request_tx.downgrade());
 
+        let running = RunningChannel {
+            working: working.clone(),
+            open: OpenRequests::default(),
+            request_tx,
+            next_request_id: Arc::new(AtomicU32::new(0)),
+            closed: closed.clone(),
+        };
         let ret = async move {
-            futures::future::try_join3(writer, reader, reply_handler).await?;
+            let r = futures::future::try_join3(writer, reader, reply_handler).await;
+            closed.store(true, Ordering::Release);
+            Self::fail_working(&working);
+            r?;
             Result::<(), ClientError>::Ok(())
         };
-        (
-            ret,
-            RunningChannel {
-                working,
-                request_tx,
-                next_request_id: Cell::new(0),
-            },
-        )
+        (ret, running)
+    }
+
+    /// Complete every pending call with `ConnectionLost`.
+    ///
+    /// A call whose reply buffer is full only has its sender dropped, it
+    /// reads the buffered replies and then `ConnectionLost` as the stream
+    /// closed without the server's `EOS`.
+    fn fail_working(working: &WorkingCalls) {
+        let pending: Vec<_> = working.lock().unwrap().drain().collect();
+        if !pending.is_empty() {
+            info!(
+                pending = pending.len(),
+                "connection lost, fail pending calls"
+            );
+        }
+        for (_, service_tx) in pending {
+            let _ = service_tx.try_send(Err(ClientError::ConnectionLost()));
+        }
     }
 
     async fn channel_writer(
@@ -99,7 +253,7 @@ impl Channel {
 
             debug!(write_frame = %frame);
         }
-        todo!()
+        Ok(())
     }
 
     async fn channel_reader(
@@ -121,37 +275,139 @@ impl Channel {
 
             reply_tx.send(frame).await?;
         }
-        todo!()
     }
 
+    /// A call in `working` has an open reply half, a frame for any other
+    /// request id is on a closed stream and resets it.
     async fn reply_handler(
         mut reply_rx: mpsc::Receiver<ReplyFrame>,
-        working: Rc<RefCell<HashMap<u32, mpsc::Sender<ReplyFrame>>>>,
+        working: WorkingCalls,
+        request_tx: mpsc::WeakSender<RequestFrame>,
     ) -> Result<(), ClientError> {
         while let Some(frame) = reply_rx.recv().await {
             let ReplyFrame {
                 header:
                     ReplyHeader {
                         request_id,
-                        status_code: _, // TODO:
+                        status_code,
                         ref flag,
                         body_len: _,
                     },
                 body: _,
             } = frame;
 
+            if is_control_request_id(request_id) {
+                debug!(request_id, "control frame ignored");
+                continue;
+            }
+
             use ReplyFlagBit::*;
             let service_tx = if flag.is(EOS) {
-                working.borrow_mut().remove(&request_id)
+                working.lock().unwrap().remove(&request_id)
             } else {
-                working.borrow_mut().get(&request_id).map(|tx| tx.clone())
-            }
-            .ok_or(ClientError::ClientRecordError())?;
+                working.lock().unwrap().get(&request_id).cloned()
+            };
+            let service_tx = match service_tx {
+                Some(service_tx) => service_tx,
+                // a cancelled call may be ended twice
+                None if flag.is(SIGNAL) && status_code != 0 => {
+                    debug!(request_id, "status for finished call");
+                    continue;
+                }
+                None => {
+                    let e = StreamError::RecvAfterEnd();
+                    info!(request_id, error = %e, "reset stream");
+                    if let Some(request_tx) = request_tx.upgrade() {
+                        request_tx.send(RequestFrame::reset(request_id)).await?;
+                    }
+                    continue;
+                }
+            };
 
-            if !flag.is(SIGNAL) {
-                service_tx.send(frame).await?;
+            // an `EOS` is passed on even without a message, the reader
+            // tells the end of the call from a lost connection by it
+            if !flag.is(SIGNAL) || status_code != 0 || flag.is(EOS) {
+                // the caller may have dropped the call, that is not a channel error
+                if service_tx.send(Ok(frame)).await.is_err() {
+                    debug!(request_id, "reply for dropped call");
+                }
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientReader;
+
+    #[test]
+    fn request_ids_skip_in_flight_and_control() {
+        let (request_tx, _request_rx) = mpsc::channel(1);
+        let channel = RunningChannel {
+            working: WorkingCalls::default(),
+            open: OpenRequests::default(),
+            request_tx,
+            next_request_id: Arc::new(AtomicU32::new(CONTROL_REQUEST_ID_MIN - 1)),
+            closed: Arc::new(AtomicBool::new(false)),
+        };
+        let (service_tx, _service_rx) = mpsc::channel(1);
+        channel.working.lock().unwrap().insert(0, service_tx);
+        // replied to, but still sending
+        channel.open.lock().unwrap().insert(1);
+
+        let last = channel.call_method(0).unwrap();
+        let wrapped = channel.call_method(0).unwrap();
+        let next = channel.call_method(0).unwrap();
+        assert_eq!(last.request_id(), CONTROL_REQUEST_ID_MIN - 1);
+        assert_eq!(wrapped.request_id(), 2);
+        assert_eq!(next.request_id(), 3);
+
+        // dropping a call which sent nothing frees the id
+        drop(wrapped);
+        assert!(!channel.open.lock().unwrap().contains(&2));
+        assert!(!channel.working.lock().unwrap().contains_key(&2));
+    }
+
+    fn reply(request_id: u32, body: &'static [u8]) -> ReplyFrame {
+        ReplyFrame {
+            header: ReplyHeader {
+                request_id,
+                flag: ReplyFlag::default(),
+                status_code: 0,
+                body_len: body.len() as u32,
+            },
+            body: Bytes::from_static(body),
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_lost_reaches_pending_calls() {
+        let working = WorkingCalls::default();
+        let (free_tx, free_rx) = mpsc::channel(1);
+        let (full_tx, full_rx) = mpsc::channel(1);
+        full_tx.try_send(Ok(reply(1, b"buffered"))).unwrap();
+        working.lock().unwrap().insert(0, free_tx);
+        working.lock().unwrap().insert(1, full_tx);
+
+        Channel::fail_working(&working);
+        assert!(working.lock().unwrap().is_empty());
+
+        let mut free = ClientReader::new(free_rx);
+        assert!(matches!(
+            free.read().await,
+            Err(ClientError::ConnectionLost())
+        ));
+        assert!(matches!(free.read().await, Ok(None)));
+
+        // no room for the error, the closed stream still reads as lost
+        let mut full = ClientReader::new(full_rx);
+        let (_, body) = full.read().await.unwrap().unwrap();
+        assert_eq!(body, Bytes::from_static(b"buffered"));
+        assert!(matches!(
+            full.read().await,
+            Err(ClientError::ConnectionLost())
+        ));
+    }
+}
@@ -1,6 +1,9 @@
 use tokio::sync::mpsc;
 
-use crate::protocol::frame::{FrameError, ReplyFrame, RequestFrame};
+use crate::protocol::{
+    frame::{FrameError, ReplyFrame, RequestFrame},
+    Status, StreamError,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
@@ -18,4 +21,34 @@ pub enum ClientError {
 
     #[error("read reply but it's request_id can't find in record")]
     ClientRecordError(),
+
+    #[error("call failed: {0}")]
+    Status(#[from] Status),
+
+    #[error("connection lost")]
+    ConnectionLost(),
+
+    #[error("channel is not connected")]
+    Unavailable(),
+
+    #[error("circuit breaker is open")]
+    CircuitOpen(),
+
+    #[error("invalid target {0}, expect rspc://name")]
+    InvalidTarget(String),
+
+    #[error("no free request id, too many calls in flight")]
+    NoRequestId(),
+
+    #[error("call ended without a reply")]
+    NoReply(),
+
+    #[error("unary call got more than one reply")]
+    UnexpectedReply(),
+
+    #[error("metadata must be written before any message")]
+    MetadataAfterWrite(),
+
+    #[error("stream error: {0}")]
+    Stream(#[from] StreamError),
 }
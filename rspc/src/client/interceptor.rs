@@ -0,0 +1,332 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::protocol::Metadata;
+
+use super::{ClientChannel, ClientError, ClientReaderWriter, UnaryCall};
+
+/// Hooks run around the calls of an `InterceptedChannel`.
+///
+/// `on_call` runs before a call is opened, the first `Err` fails it.
+/// `on_metadata` sees the outgoing call metadata, an empty one if the
+/// caller sends none, and a non-empty result is sent before the first
+/// message. `on_error` sees a call failing without a status reply, e.g.
+/// on `ConnectionLost` or `CircuitOpen`.
+pub trait Interceptor: Send + Sync {
+    fn on_call(&self, _method_id: u32) -> Result<(), ClientError> {
+        Ok(())
+    }
+
+    fn on_metadata(&self, _method_id: u32, _metadata: &mut Metadata) {}
+
+    fn on_request(&self, _method_id: u32, _body: &Bytes) {}
+
+    fn on_reply(&self, _method_id: u32, _status_code: u32, _body: &Bytes) {}
+
+    fn on_error(&self, _method_id: u32, _error: &ClientError) {}
+}
+
+#[derive(Clone, Default)]
+pub struct InterceptorChain {
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl InterceptorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<I: 'static + Interceptor>(&mut self, interceptor: I) {
+        self.interceptors.push(Arc::new(interceptor));
+    }
+
+    pub fn with<I: 'static + Interceptor>(mut self, interceptor: I) -> Self {
+        self.push(interceptor);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// `self` runs first, then `inner`.
+    pub(crate) fn chain(&self, inner: &InterceptorChain) -> InterceptorChain {
+        let mut interceptors = self.interceptors.clone();
+        interceptors.extend(inner.interceptors.iter().cloned());
+        InterceptorChain { interceptors }
+    }
+
+    pub(crate) fn on_call(&self, method_id: u32) -> Result<(), ClientError> {
+        for i in self.interceptors.iter() {
+            i.on_call(method_id)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn on_metadata(&self, method_id: u32, metadata: &mut Metadata) {
+        for i in self.interceptors.iter() {
+            i.on_metadata(method_id, metadata);
+        }
+    }
+
+    pub(crate) fn on_request(&self, method_id: u32, body: &Bytes) {
+        for i in self.interceptors.iter() {
+            i.on_request(method_id, body);
+        }
+    }
+
+    pub(crate) fn on_reply(&self, method_id: u32, status_code: u32, body: &Bytes) {
+        for i in self.interceptors.iter() {
+            i.on_reply(method_id, status_code, body);
+        }
+    }
+
+    pub(crate) fn on_error(&self, method_id: u32, error: &ClientError) {
+        for i in self.interceptors.iter() {
+            i.on_error(method_id, error);
+        }
+    }
+}
+
+/// Frame observer installed into a call's reader and writer.
+#[derive(Clone)]
+pub(crate) struct CallObserver {
+    pub(crate) method_id: u32,
+    pub(crate) chain: InterceptorChain,
+}
+
+/// Runs `chain` around every call of `inner`. Wrap a channel once to
+/// intercept all its stubs, or wrap a clone per stub.
+///
+/// Interceptors of an outer channel run before those of the one it wraps.
+#[derive(Clone)]
+pub struct InterceptedChannel<C> {
+    inner: C,
+    chain: InterceptorChain,
+}
+
+impl<C: ClientChannel> InterceptedChannel<C> {
+    pub fn new(inner: C, chain: InterceptorChain) -> Self {
+        Self { inner, chain }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<C: ClientChannel> ClientChannel for InterceptedChannel<C> {
+    async fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError> {
+        self.chain.on_call(method_id)?;
+        let mut rw = self.inner.call_method(method_id).await?;
+        rw.intercept(&self.chain);
+        Ok(rw)
+    }
+
+    async fn start_unary(&self, call: &UnaryCall) -> Result<ClientReaderWriter, ClientError> {
+        let call = self.before_unary(call)?;
+        match self.inner.start_unary(&call).await {
+            Ok(mut rw) => {
+                // the request is sent, only the reply hooks are left to run
+                rw.intercept(&self.chain);
+                Ok(rw)
+            }
+            Err(e) => {
+                self.chain.on_error(call.method_id, &e);
+                Err(e)
+            }
+        }
+    }
+
+    // the hooks run on the whole call, `inner` may send it more than once
+    async fn unary(&self, call: &UnaryCall) -> Result<(u32, Bytes), ClientError> {
+        let call = self.before_unary(call)?;
+        let result = self.inner.unary(&call).await;
+        match &result {
+            Ok((status_code, body)) => self.chain.on_reply(call.method_id, *status_code, body),
+            Err(e) => self.chain.on_error(call.method_id, e),
+        }
+        result
+    }
+}
+
+impl<C> InterceptedChannel<C> {
+    /// Run the hooks up to the request on `call`.
+    fn before_unary(&self, call: &UnaryCall) -> Result<UnaryCall, ClientError> {
+        let method_id = call.method_id;
+        self.chain.on_call(method_id)?;
+        let mut call = call.clone();
+        let mut metadata = call.metadata.take().unwrap_or_default();
+        self.chain.on_metadata(method_id, &mut metadata);
+        call.metadata = (!metadata.is_empty()).then_some(metadata);
+        self.chain.on_request(method_id, &call.request);
+        Ok(call)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use futures::poll;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::protocol::frame::{
+        FrameFlag, ReplyFlag, ReplyFlagBit, ReplyFrame, ReplyHeader, RequestFlagBit, RequestFrame,
+    };
+
+    /// Adds an auth token, counting how often it ran, and counts errors.
+    #[derive(Clone, Default)]
+    struct Auth {
+        runs: Arc<AtomicUsize>,
+        errors: Arc<AtomicUsize>,
+    }
+
+    impl Interceptor for Auth {
+        fn on_metadata(&self, _method_id: u32, metadata: &mut Metadata) {
+            self.runs.fetch_add(1, Ordering::Relaxed);
+            metadata.insert("authorization", "token");
+        }
+
+        fn on_error(&self, _method_id: u32, error: &ClientError) {
+            assert!(matches!(error, ClientError::ConnectionLost()));
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Hands the request frames of its one call to the test.
+    #[derive(Default)]
+    struct Recorder {
+        call: Mutex<Option<(mpsc::Receiver<RequestFrame>, mpsc::Sender<ReplyResult>)>>,
+        // frames queued ahead of the call, filling the request channel
+        queued: usize,
+    }
+
+    type ReplyResult = Result<ReplyFrame, ClientError>;
+
+    #[async_trait]
+    impl ClientChannel for Recorder {
+        async fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError> {
+            let (request_tx, request_rx) = mpsc::channel(self.queued.max(1));
+            for _ in 0..self.queued {
+                request_tx.try_send(RequestFrame::reset(0)).unwrap();
+            }
+            let (reply_tx, reply_rx) = mpsc::channel(1);
+            *self.call.lock().unwrap() = Some((request_rx, reply_tx));
+            Ok(ClientReaderWriter::new(request_tx, reply_rx, 1, method_id))
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> (mpsc::Receiver<RequestFrame>, mpsc::Sender<ReplyResult>) {
+            self.call.lock().unwrap().take().unwrap()
+        }
+    }
+
+    fn assert_auth(frame: &RequestFrame) {
+        assert!(frame.header.flag.is(RequestFlagBit::METADATA));
+        let metadata = Metadata::decode(frame.body.clone()).unwrap();
+        assert_eq!(metadata.get("authorization"), Some("token"));
+    }
+
+    #[tokio::test]
+    async fn unary_metadata() {
+        let auth = Auth::default();
+        let channel = InterceptedChannel::new(
+            Recorder::default(),
+            InterceptorChain::new().with(auth.clone()),
+        );
+        let call = UnaryCall::new(0, Bytes::from_static(b"request"));
+        let server = async {
+            while channel.inner().call.lock().unwrap().is_none() {
+                tokio::task::yield_now().await;
+            }
+            let (mut request_rx, reply_tx) = channel.inner().take();
+            assert_auth(&request_rx.recv().await.unwrap());
+            let request = request_rx.recv().await.unwrap();
+            assert_eq!(request.body, Bytes::from_static(b"request"));
+            let reply = ReplyFrame {
+                header: ReplyHeader {
+                    request_id: 1,
+                    flag: ReplyFlag::default().set(ReplyFlagBit::EOS),
+                    status_code: 0,
+                    body_len: 5,
+                },
+                body: Bytes::from_static(b"reply"),
+            };
+            reply_tx.send(Ok(reply)).await.unwrap();
+        };
+        let (reply, ()) = tokio::join!(channel.unary(&call), server);
+        assert_eq!(reply.unwrap(), (0, Bytes::from_static(b"reply")));
+        assert_eq!(auth.runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn call_method_metadata() {
+        let auth = Auth::default();
+        let inner = Recorder {
+            queued: 1,
+            ..Recorder::default()
+        };
+        let channel = InterceptedChannel::new(inner, InterceptorChain::new().with(auth.clone()));
+        let mut rw = channel.call_method(0).await.unwrap();
+        let (mut request_rx, _reply_tx) = channel.inner().take();
+
+        // no room for the metadata yet, it is built once however often
+        // the write is polled
+        let write = rw.write(Bytes::from_static(b"request"));
+        tokio::pin!(write);
+        for _ in 0..3 {
+            assert!(poll!(write.as_mut()).is_pending());
+        }
+        request_rx.recv().await.unwrap();
+        let server = async {
+            assert_auth(&request_rx.recv().await.unwrap());
+            let request = request_rx.recv().await.unwrap();
+            assert_eq!(request.body, Bytes::from_static(b"request"));
+        };
+        let (written, ()) = tokio::join!(write, server);
+        written.unwrap();
+        assert_eq!(auth.runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn errors_reach_interceptors() {
+        let auth = Auth::default();
+        let channel = InterceptedChannel::new(
+            Recorder::default(),
+            InterceptorChain::new().with(auth.clone()),
+        );
+
+        // the connection dies before the reply
+        let call = UnaryCall::new(0, Bytes::from_static(b"request"));
+        let server = async {
+            while channel.inner().call.lock().unwrap().is_none() {
+                tokio::task::yield_now().await;
+            }
+            // read the whole call, then drop the replies
+            let (mut request_rx, _) = channel.inner().take();
+            assert_auth(&request_rx.recv().await.unwrap());
+            request_rx.recv().await.unwrap();
+            request_rx
+        };
+        let (reply, _request_rx) = tokio::join!(channel.unary(&call), server);
+        assert!(matches!(reply, Err(ClientError::ConnectionLost())));
+        assert_eq!(auth.errors.load(Ordering::Relaxed), 1);
+
+        let mut rw = channel.call_method(0).await.unwrap();
+        let (_request_rx, _) = channel.inner().take();
+        assert!(matches!(
+            rw.read().await,
+            Err(ClientError::ConnectionLost())
+        ));
+        assert_eq!(auth.errors.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use tokio::{net::ToSocketAddrs, sync::watch, time};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use super::{Channel, ClientChannel, ClientError, ClientReaderWriter, RunningChannel};
+
+/// Connectivity of a `ManagedChannel`, published to `subscribe` receivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectivityState {
+    // not started or shut down
+    Idle,
+    Connecting,
+    Ready,
+    // the last connect failed or the connection was lost, waiting to retry
+    TransientFailure,
+}
+
+/// Delay between reconnect attempts, `initial * multiplier^attempt` capped
+/// at `max`, then moved randomly by up to `jitter` of itself.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(120),
+            multiplier: 1.6,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let base = base.min(self.max.as_secs_f64());
+        let spread = base * self.jitter * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_secs_f64((base + spread).max(0.0))
+    }
+}
+
+/// What a call does while the channel is not `Ready`.
+#[derive(Debug, Clone, Copy)]
+pub enum CallPolicy {
+    // fail with `ClientError::Unavailable` at once
+    FailFast,
+    // wait up to `timeout` for the channel to become ready
+    Queue { timeout: Duration },
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        CallPolicy::Queue {
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Run after every connect, before the channel is `Ready`. An error drops
+/// the connection and counts as a failed attempt.
+pub type Handshake =
+    Arc<dyn Fn(RunningChannel) -> BoxFuture<'static, Result<(), ClientError>> + Send + Sync>;
+
+/// A channel to one address which reconnects when the connection is lost.
+pub struct ManagedChannel<A> {
+    addr: A,
+    backoff: Backoff,
+    policy: CallPolicy,
+    handshake: Option<Handshake>,
+}
+
+/// Handle of a running `ManagedChannel`, calls go to the current connection.
+#[derive(Clone)]
+pub struct RunningManagedChannel {
+    current: Arc<Mutex<Option<RunningChannel>>>,
+    state: watch::Receiver<ConnectivityState>,
+    policy: CallPolicy,
+    shutdown: CancellationToken,
+}
+
+impl<A> ManagedChannel<A>
+where
+    A: ToSocketAddrs + Clone + 'static,
+{
+    pub fn new(addr: A) -> Self {
+        Self {
+            addr,
+            backoff: Backoff::default(),
+            policy: CallPolicy::default(),
+            handshake: None,
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn call_policy(mut self, policy: CallPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = Some(handshake);
+        self
+    }
+
+    /// The returned future connects and reconnects until
+    /// `RunningManagedChannel::shutdown`.
+    pub fn run(
+        self,
+    ) -> (
+        impl futures::Future<Output = ()> + 'static,
+        RunningManagedChannel,
+    ) {
+        let (state_tx, state) = watch::channel(ConnectivityState::Idle);
+        let current = Arc::new(Mutex::new(None));
+        let shutdown = CancellationToken::new();
+        let running = RunningManagedChannel {
+            current: current.clone(),
+            state,
+            policy: self.policy,
+            shutdown: shutdown.clone(),
+        };
+
+        let ret = async move {
+            tokio::select! {
+                _ = shutdown.cancelled() => {}
+                _ = self.reconnect_loop(&current, &state_tx) => {}
+            }
+            // the connection's run future is dropped, its calls end here
+            if let Some(channel) = current.lock().unwrap().take() {
+                channel.close();
+            }
+            state_tx.send_replace(ConnectivityState::Idle);
+        };
+        (ret, running)
+    }
+
+    async fn reconnect_loop(
+        &self,
+        current: &Mutex<Option<RunningChannel>>,
+        state_tx: &watch::Sender<ConnectivityState>,
+    ) {
+        let mut attempt = 0;
+        loop {
+            state_tx.send_replace(ConnectivityState::Connecting);
+            match Channel::new(self.addr.clone()).await {
+                Ok(channel) => {
+                    let (run, running) = channel.run();
+                    tokio::pin!(run);
+                    let handshake = async {
+                        match &self.handshake {
+                            Some(handshake) => handshake(running.clone()).await,
+                            None => Ok(()),
+                        }
+                    };
+                    let ready = tokio::select! {
+                        r = &mut run => r.and(Err(ClientError::ConnectionLost())),
+                        r = handshake => r,
+                    };
+                    match ready {
+                        Ok(()) => {
+                            info!("channel ready");
+                            attempt = 0;
+                            *current.lock().unwrap() = Some(running);
+                            state_tx.send_replace(ConnectivityState::Ready);
+                            let r = run.await;
+                            current.lock().unwrap().take();
+                            info!(result = ?r, "connection lost, reconnect");
+                        }
+                        Err(e) => info!(attempt, error = %e, "handshake failed"),
+                    }
+                }
+                Err(e) => info!(attempt, error = %e, "connect failed"),
+            }
+            state_tx.send_replace(ConnectivityState::TransientFailure);
+            time::sleep(self.backoff.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl RunningManagedChannel {
+    pub fn state(&self) -> ConnectivityState {
+        *self.state.borrow()
+    }
+
+    /// A receiver seeing every state change.
+    pub fn subscribe(&self) -> watch::Receiver<ConnectivityState> {
+        self.state.clone()
+    }
+
+    /// Stop reconnecting and drop the connection, pending calls fail with
+    /// `ConnectionLost` and the run future returns.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// The current connection, if `Ready`.
+    pub fn connected(&self) -> Option<RunningChannel> {
+        let current = self.current.lock().unwrap();
+        current.as_ref().filter(|c| !c.is_closed()).cloned()
+    }
+
+    /// Calls still waiting for their last reply on the current connection.
+    pub fn in_flight(&self) -> usize {
+        let current = self.current.lock().unwrap();
+        current.as_ref().map_or(0, |c| c.in_flight())
+    }
+
+    /// Wait until a connection is ready.
+    pub async fn ready(&self) -> Result<RunningChannel, ClientError> {
+        let mut state = self.state.clone();
+        loop {
+            if let Some(channel) = self.connected() {
+                return Ok(channel);
+            }
+            if self.shutdown.is_cancelled() || state.changed().await.is_err() {
+                return Err(ClientError::ConnectionLost());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ClientChannel for RunningManagedChannel {
+    async fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError> {
+        let channel = match self.policy {
+            CallPolicy::FailFast => self.connected().ok_or(ClientError::Unavailable())?,
+            CallPolicy::Queue { timeout } => time::timeout(timeout, self.ready())
+                .await
+                .map_err(|_| ClientError::Unavailable())??,
+        };
+        channel.call_method(method_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use futures::FutureExt;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, Semaphore},
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Accept connections on `listener`, each one is handed to the test.
+    fn accept(listener: TcpListener) -> (JoinHandle<()>, mpsc::UnboundedReceiver<TcpStream>) {
+        let (stream_tx, stream_rx) = mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = stream_tx.send(stream);
+            }
+        });
+        (server, stream_rx)
+    }
+
+    /// A handshake which holds every connection in `Connecting` until the
+    /// test adds a permit.
+    fn gate() -> (Arc<Semaphore>, Handshake) {
+        let permits = Arc::new(Semaphore::new(0));
+        let handshake: Handshake = {
+            let permits = permits.clone();
+            Arc::new(move |_| {
+                let permits = permits.clone();
+                async move {
+                    permits.acquire().await.unwrap().forget();
+                    Ok(())
+                }
+                .boxed()
+            })
+        };
+        (permits, handshake)
+    }
+
+    fn managed(
+        addr: SocketAddr,
+        handshake: Handshake,
+        policy: CallPolicy,
+    ) -> RunningManagedChannel {
+        let backoff = Backoff {
+            initial: Duration::from_millis(20),
+            max: Duration::from_millis(20),
+            multiplier: 1.0,
+            jitter: 0.0,
+        };
+        let (run, channel) = ManagedChannel::new(addr)
+            .backoff(backoff)
+            .call_policy(policy)
+            .handshake(handshake)
+            .run();
+        tokio::spawn(run);
+        channel
+    }
+
+    async fn wait_state(state: &mut watch::Receiver<ConnectivityState>, want: ConnectivityState) {
+        time::timeout(WAIT, state.wait_for(|s| *s == want))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_server_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server, mut streams) = accept(listener);
+        let (permits, handshake) = gate();
+        let channel = managed(addr, handshake, CallPolicy::FailFast);
+        let mut state = channel.subscribe();
+        use ConnectivityState::*;
+
+        // held by the handshake until a permit is added
+        wait_state(&mut state, Connecting).await;
+        assert!(channel.connected().is_none());
+        permits.add_permits(1);
+        wait_state(&mut state, Ready).await;
+        assert!(channel.connected().is_some());
+
+        // the server goes down, connects fail until it is back
+        server.abort();
+        drop(streams.recv().await);
+        wait_state(&mut state, TransientFailure).await;
+        assert!(channel.connected().is_none());
+
+        let (_server, _streams) = accept(TcpListener::bind(addr).await.unwrap());
+        wait_state(&mut state, Connecting).await;
+        permits.add_permits(1);
+        wait_state(&mut state, Ready).await;
+        assert!(channel.connected().is_some());
+
+        channel.shutdown();
+        wait_state(&mut state, Idle).await;
+    }
+
+    #[tokio::test]
+    async fn call_policy_while_disconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_server, _streams) = accept(listener);
+        let (permits, handshake) = gate();
+        let fail_fast = managed(addr, handshake.clone(), CallPolicy::FailFast);
+        let queue = managed(addr, handshake, CallPolicy::Queue { timeout: WAIT });
+        wait_state(&mut fail_fast.subscribe(), ConnectivityState::Connecting).await;
+        wait_state(&mut queue.subscribe(), ConnectivityState::Connecting).await;
+
+        assert!(matches!(
+            fail_fast.call_method(0).await,
+            Err(ClientError::Unavailable())
+        ));
+        let queued = queue.call_method(0);
+        tokio::pin!(queued);
+        assert!(futures::poll!(&mut queued).is_pending());
+        permits.add_permits(2);
+        assert!(time::timeout(WAIT, queued).await.unwrap().is_ok());
+
+        wait_state(&mut fail_fast.subscribe(), ConnectivityState::Ready).await;
+        assert!(fail_fast.call_method(0).await.is_ok());
+        fail_fast.shutdown();
+        queue.shutdown();
+    }
+
+    #[tokio::test]
+    async fn shutdown_fails_pending_calls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_server, _streams) = accept(listener);
+        let (permits, handshake) = gate();
+        permits.add_permits(1);
+        let channel = managed(addr, handshake, CallPolicy::FailFast);
+        let mut state = channel.subscribe();
+        wait_state(&mut state, ConnectivityState::Ready).await;
+
+        // the server never replies
+        let mut rw = channel.call_method(0).await.unwrap();
+        rw.write_last(bytes::Bytes::new()).await.unwrap();
+        channel.shutdown();
+        let reply = time::timeout(WAIT, rw.read_unary()).await.unwrap();
+        assert!(matches!(reply, Err(ClientError::ConnectionLost())));
+        wait_state(&mut state, ConnectivityState::Idle).await;
+    }
+
+    #[test]
+    fn backoff_grows_to_max() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(4));
+        assert_eq!(backoff.delay(100), Duration::from_secs(10));
+
+        let backoff = Backoff {
+            jitter: 0.5,
+            ..backoff
+        };
+        for attempt in 0..10 {
+            let delay = backoff.delay(attempt).as_secs_f64();
+            let base = 2f64.powi(attempt as i32).min(10.0);
+            assert!(delay >= base * 0.5 && delay <= base * 1.5);
+        }
+    }
+}
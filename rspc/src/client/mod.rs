@@ -1,9 +1,32 @@
+pub mod balance;
+pub mod blocking;
+pub mod breaker;
 pub mod channel;
 pub mod error;
+pub mod interceptor;
+pub mod managed;
+pub mod resolve;
+pub mod retry;
 pub mod service;
+pub mod streaming;
+pub mod tower_adapter;
 
+pub use balance::{BalancePolicy, BalancedChannel, RunningBalancedChannel};
+pub use blocking::{request_stream, BlockingChannel, BlockingStream};
+pub use breaker::{BreakerConfig, BreakerState, CircuitBreaker, CircuitKey, Trial};
 pub use channel::Channel;
+pub use channel::ClientChannel;
 pub use channel::RunningChannel;
+pub use channel::SharedChannel;
 pub use error::ClientError;
+pub use interceptor::{InterceptedChannel, Interceptor, InterceptorChain};
+pub use managed::{Backoff, CallPolicy, ConnectivityState, ManagedChannel, RunningManagedChannel};
+pub use resolve::{DnsResolver, FileResolver, Resolver, StaticResolver};
+pub use retry::{HedgePolicy, RetryBudget, RetryChannel, RetryPolicy};
 pub use service::ClientReaderWriter;
 pub use service::ClientStub;
+pub use service::UnaryCall;
+pub use service::{call_bidi, call_client_stream, call_server_stream, call_unary};
+pub use service::{ClientReader, ClientWriter};
+pub use streaming::{ReplyStream, RequestSink};
+pub use tower_adapter::UnaryService;
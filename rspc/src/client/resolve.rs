@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use futures::{stream::BoxStream, StreamExt};
+use tokio::{net::lookup_host, time};
+use tracing::info;
+
+use super::ClientError;
+
+const TARGET_SCHEME: &str = "rspc://";
+
+/// The name of `rspc://name`.
+pub fn parse_target(target: &str) -> Result<&str, ClientError> {
+    match target.strip_prefix(TARGET_SCHEME) {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => Err(ClientError::InvalidTarget(target.to_string())),
+    }
+}
+
+/// Turns a target name into the addresses serving it.
+pub trait Resolver {
+    /// Address sets of `name`, a new one every time the set changes.
+    fn resolve(&self, name: &str) -> BoxStream<'static, Vec<SocketAddr>>;
+}
+
+/// Fixed address sets.
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    targets: HashMap<String, Vec<SocketAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn target(mut self, name: &str, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.targets
+            .insert(name.to_string(), addrs.into_iter().collect());
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, name: &str) -> BoxStream<'static, Vec<SocketAddr>> {
+        let addrs = self.targets.get(name).cloned().unwrap_or_default();
+        futures::stream::once(async move { addrs }).boxed()
+    }
+}
+
+/// Looks `host:port` up every `interval`.
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    interval: Duration,
+}
+
+impl DnsResolver {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval }
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn resolve(&self, name: &str) -> BoxStream<'static, Vec<SocketAddr>> {
+        let host = name.to_string();
+        let interval = self.interval;
+        let sets = futures::stream::unfold(true, move |first| {
+            let host = host.clone();
+            async move {
+                if !first {
+                    time::sleep(interval).await;
+                }
+                let set = match lookup_host(&host).await {
+                    Ok(addrs) => Some(addrs.collect()),
+                    Err(e) => {
+                        info!(%host, error = %e, "dns lookup failed");
+                        None
+                    }
+                };
+                Some((set, false))
+            }
+        });
+        changes(sets)
+    }
+}
+
+/// Reads `name -> addresses` from a file, again whenever its modify time
+/// changes, checked every `interval`.
+///
+/// A `.json` file holds an object, `{"orders": ["10.0.0.1:8080"]}`, any
+/// other file is read as TOML with an array of addresses per target,
+/// `orders = ["10.0.0.1:8080"]`.
+#[derive(Debug, Clone)]
+pub struct FileResolver {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl FileResolver {
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> Self {
+        Self {
+            path: path.into(),
+            interval,
+        }
+    }
+}
+
+impl Resolver for FileResolver {
+    fn resolve(&self, name: &str) -> BoxStream<'static, Vec<SocketAddr>> {
+        let path = self.path.clone();
+        let name = name.to_string();
+        let interval = self.interval;
+        let sets = futures::stream::unfold((true, None), move |(first, last)| {
+            let path = path.clone();
+            let name = name.clone();
+            async move {
+                if !first {
+                    time::sleep(interval).await;
+                }
+                match read_if_modified(&path, last).await {
+                    Ok((modified, targets)) => {
+                        let set = targets.map(|t| t.get(&name).cloned().unwrap_or_default());
+                        Some((set, (false, Some(modified))))
+                    }
+                    Err(e) => {
+                        info!(path = %path.display(), error = %e, "read resolver file failed");
+                        Some((None, (false, last)))
+                    }
+                }
+            }
+        });
+        changes(sets)
+    }
+}
+
+async fn read_if_modified(
+    path: &Path,
+    last: Option<SystemTime>,
+) -> Result<(SystemTime, Option<HashMap<String, Vec<SocketAddr>>>), std::io::Error> {
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    if Some(modified) == last {
+        return Ok((modified, None));
+    }
+    let content = tokio::fs::read_to_string(path).await?;
+    let json = path.extension().is_some_and(|e| e == "json");
+    Ok((modified, Some(parse_targets(&content, json)?)))
+}
+
+/// Keep the sets which differ from the one before, skipping `None`.
+fn changes(
+    sets: impl futures::Stream<Item = Option<Vec<SocketAddr>>> + Send + 'static,
+) -> BoxStream<'static, Vec<SocketAddr>> {
+    let mut last: Option<Vec<SocketAddr>> = None;
+    sets.filter_map(move |set| {
+        let set = set.map(|mut set| {
+            set.sort();
+            set.dedup();
+            set
+        });
+        let changed = match set {
+            Some(set) if last.as_ref() != Some(&set) => {
+                last = Some(set.clone());
+                Some(set)
+            }
+            _ => None,
+        };
+        async move { changed }
+    })
+    .boxed()
+}
+
+fn parse_targets(
+    content: &str,
+    json: bool,
+) -> Result<HashMap<String, Vec<SocketAddr>>, std::io::Error> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let targets: HashMap<String, Vec<String>> = if json {
+        serde_json::from_str(content).map_err(|e| invalid(e.to_string()))?
+    } else {
+        toml::from_str(content).map_err(|e| invalid(e.to_string()))?
+    };
+    targets
+        .into_iter()
+        .map(|(name, addrs)| {
+            let addrs = addrs
+                .iter()
+                .map(|a| a.parse().map_err(|_| invalid(format!("bad address {}", a))))
+                .collect::<Result<_, _>>()?;
+            Ok((name, addrs))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGETS: &str = r#"
+# targets
+orders = [
+    "10.0.0.1:8080", # primary
+    '10.0.0.2:8080',
+]
+'billing.v2' = ["10.0.0.3:8080"]
+"#;
+
+    #[test]
+    fn parse_target_files() {
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let json = parse_targets(r#"{"orders": ["10.0.0.1:8080"]}"#, true).unwrap();
+        assert_eq!(json["orders"], vec![addr]);
+
+        let toml = "# targets\norders = [\"10.0.0.1:8080\", \"10.0.0.2:8080\"]\n";
+        let toml = parse_targets(toml, false).unwrap();
+        assert_eq!(toml["orders"].len(), 2);
+
+        let toml = parse_targets(TARGETS, false).unwrap();
+        assert_eq!(toml["orders"].len(), 2);
+        assert_eq!(toml["billing.v2"], vec!["10.0.0.3:8080".parse().unwrap()]);
+
+        assert!(parse_targets("orders = [\"nope\"]", false).is_err());
+        assert!(parse_targets("[orders]\naddrs = []", false).is_err());
+        assert_eq!(parse_target("rspc://orders").unwrap(), "orders");
+        assert!(parse_target("orders").is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_toml_file() {
+        let path = std::env::temp_dir().join(format!("rspc-targets-{}.toml", std::process::id()));
+        std::fs::write(&path, TARGETS).unwrap();
+        let resolver = FileResolver::new(&path, Duration::from_secs(60));
+        let set = resolver.resolve("orders").next().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let expect: Vec<SocketAddr> = vec![
+            "10.0.0.1:8080".parse().unwrap(),
+            "10.0.0.2:8080".parse().unwrap(),
+        ];
+        assert_eq!(set, expect);
+    }
+}
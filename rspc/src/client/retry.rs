@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::time;
+use tracing::debug;
+
+use crate::protocol::Code;
+
+use super::{
+    managed::Backoff, ClientChannel, ClientError, ClientReader, ClientReaderWriter, ClientWriter,
+    UnaryCall,
+};
+
+/// When and how often an idempotent unary call is sent again.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // the first attempt included, 1 disables retries
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    // status codes worth another attempt, transport errors always are
+    pub retryable: Vec<Code>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Backoff {
+                initial: Duration::from_millis(100),
+                max: Duration::from_secs(2),
+                multiplier: 2.0,
+                jitter: 0.2,
+            },
+            retryable: vec![Code::Unavailable],
+        }
+    }
+}
+
+/// Send an idempotent unary call again, to whichever connection `inner`
+/// picks, when no reply came within `delay`. The first reply wins and the
+/// other attempts are cancelled. Extra attempts draw on the `RetryBudget`
+/// like retries do.
+#[derive(Debug, Clone)]
+pub struct HedgePolicy {
+    // the first attempt included
+    pub max_attempts: u32,
+    pub delay: Duration,
+    // status codes on which the next attempt starts at once instead of
+    // ending the call, transport errors always do
+    pub non_fatal: Vec<Code>,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 2,
+            delay: Duration::from_millis(50),
+            non_fatal: vec![Code::Unavailable],
+        }
+    }
+}
+
+/// Retry throttling shared by the calls of a channel.
+///
+/// A retryable failure takes a token, any other result gives back
+/// `token_ratio`, retries stop while at most half of `max_tokens` is left.
+#[derive(Debug, Clone)]
+pub struct RetryBudget {
+    max_tokens: f64,
+    token_ratio: f64,
+    tokens: Arc<Mutex<f64>>,
+}
+
+impl RetryBudget {
+    pub fn new(max_tokens: f64, token_ratio: f64) -> Self {
+        Self {
+            max_tokens,
+            token_ratio,
+            tokens: Arc::new(Mutex::new(max_tokens)),
+        }
+    }
+
+    fn on_failure(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens - 1.0).max(0.0);
+    }
+
+    fn on_success(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.token_ratio).min(self.max_tokens);
+    }
+
+    fn allow(&self) -> bool {
+        *self.tokens.lock().unwrap() > self.max_tokens / 2.0
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new(10.0, 0.1)
+    }
+}
+
+/// Retries or hedges idempotent unary calls of `inner`, other calls pass
+/// through. A method with a hedge policy is hedged instead of retried.
+#[derive(Clone)]
+pub struct RetryChannel<C> {
+    inner: C,
+    policy: RetryPolicy,
+    methods: HashMap<u32, RetryPolicy>,
+    hedge: Option<HedgePolicy>,
+    hedge_methods: HashMap<u32, HedgePolicy>,
+    budget: RetryBudget,
+}
+
+impl<C: ClientChannel> RetryChannel<C> {
+    pub fn new(inner: C, policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            methods: HashMap::new(),
+            hedge: None,
+            hedge_methods: HashMap::new(),
+            budget: RetryBudget::default(),
+        }
+    }
+
+    /// Use `policy` for `method_id` in place of the channel policy.
+    pub fn method(mut self, method_id: u32, policy: RetryPolicy) -> Self {
+        self.methods.insert(method_id, policy);
+        self
+    }
+
+    /// Hedge every idempotent method.
+    pub fn hedge(mut self, policy: HedgePolicy) -> Self {
+        self.hedge = Some(policy);
+        self
+    }
+
+    /// Hedge `method_id`.
+    pub fn hedge_method(mut self, method_id: u32, policy: HedgePolicy) -> Self {
+        self.hedge_methods.insert(method_id, policy);
+        self
+    }
+
+    pub fn budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+fn is_transient(e: &ClientError) -> bool {
+    matches!(
+        e,
+        ClientError::ConnectionLost()
+            | ClientError::Unavailable()
+            | ClientError::IoError(_)
+            | ClientError::RequestChannelSendError(_)
+    )
+}
+
+impl<C: ClientChannel> RetryChannel<C> {
+    async fn hedged(
+        &self,
+        call: &UnaryCall,
+        policy: &HedgePolicy,
+    ) -> Result<(u32, Bytes), ClientError> {
+        let mut running = FuturesUnordered::new();
+        let mut writers = HashMap::new();
+        let mut started = 0;
+        let mut result = Err(ClientError::Unavailable());
+        loop {
+            // every pass follows a hedge delay or a non-fatal reply
+            if self.can_hedge(started, policy) {
+                started += 1;
+                match self.start(call).await {
+                    Ok((mut reader, writer)) => {
+                        writers.insert(started, writer);
+                        let attempt = started;
+                        running.push(async move { (attempt, reader.read_unary().await) });
+                    }
+                    Err(e) => {
+                        self.budget.on_failure();
+                        result = Err(e);
+                        continue;
+                    }
+                }
+            }
+            if running.is_empty() {
+                return result;
+            }
+            let can_hedge = self.can_hedge(started, policy);
+            tokio::select! {
+                Some((attempt, reply)) = running.next() => {
+                    writers.remove(&attempt);
+                    let non_fatal = match &reply {
+                        Ok((status_code, _)) => {
+                            *status_code != 0
+                                && policy.non_fatal.contains(&Code::from(*status_code))
+                        }
+                        Err(e) => is_transient(e),
+                    };
+                    if non_fatal {
+                        self.budget.on_failure();
+                        result = reply;
+                        continue;
+                    }
+                    self.budget.on_success();
+                    for (_, mut writer) in writers.drain() {
+                        if let Err(e) = writer.cancel().await {
+                            debug!(error = %e, "cancel hedged call failed");
+                        }
+                    }
+                    return reply;
+                }
+                _ = time::sleep(policy.delay), if can_hedge => {
+                    debug!(method_id = call.method_id, started, "hedge call");
+                }
+            }
+        }
+    }
+
+    /// The first attempt always starts, the others while the budget allows.
+    fn can_hedge(&self, started: u32, policy: &HedgePolicy) -> bool {
+        started < policy.max_attempts && (started == 0 || self.budget.allow())
+    }
+
+    /// Open and send one attempt of `call`, counted by `inner` like any
+    /// other attempt.
+    async fn start(&self, call: &UnaryCall) -> Result<(ClientReader, ClientWriter), ClientError> {
+        Ok(self.inner.start_unary(call).await?.split())
+    }
+}
+
+#[async_trait]
+impl<C: ClientChannel> ClientChannel for RetryChannel<C> {
+    async fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError> {
+        self.inner.call_method(method_id).await
+    }
+
+    // one attempt, a caller driving attempts itself does the retrying
+    async fn start_unary(&self, call: &UnaryCall) -> Result<ClientReaderWriter, ClientError> {
+        self.inner.start_unary(call).await
+    }
+
+    async fn unary(&self, call: &UnaryCall) -> Result<(u32, Bytes), ClientError> {
+        if !call.idempotent {
+            return self.inner.unary(call).await;
+        }
+        let hedge = self.hedge_methods.get(&call.method_id);
+        if let Some(hedge) = hedge.or(self.hedge.as_ref()) {
+            return self.hedged(call, hedge).await;
+        }
+        let policy = self.methods.get(&call.method_id).unwrap_or(&self.policy);
+        let mut attempt = 1;
+        loop {
+            let result = self.inner.unary(call).await;
+            let retryable = match &result {
+                Ok((status_code, _)) => {
+                    *status_code != 0 && policy.retryable.contains(&Code::from(*status_code))
+                }
+                Err(e) => is_transient(e),
+            };
+            if !retryable {
+                self.budget.on_success();
+                return result;
+            }
+            self.budget.on_failure();
+            if attempt >= policy.max_attempts || !self.budget.allow() {
+                return result;
+            }
+            debug!(method_id = call.method_id, attempt, "retry call");
+            time::sleep(policy.backoff.delay(attempt - 1)).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        client::channel::ReplyResult,
+        protocol::frame::{
+            FrameFlag, ReplyFlag, ReplyFlagBit, ReplyFrame, ReplyHeader, RequestFlagBit,
+            RequestFrame,
+        },
+    };
+
+    type OpenCall = (mpsc::Receiver<RequestFrame>, mpsc::Sender<ReplyResult>);
+
+    /// Records every call, the test plays the server.
+    #[derive(Clone, Default)]
+    struct Calls(Arc<Mutex<Vec<OpenCall>>>);
+
+    impl Calls {
+        fn len(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+
+        async fn reply(&self, n: usize, body: &'static [u8]) {
+            let reply_tx = self.0.lock().unwrap()[n].1.clone();
+            let frame = ReplyFrame {
+                header: ReplyHeader {
+                    request_id: n as u32,
+                    flag: ReplyFlag::default().set(ReplyFlagBit::EOS),
+                    status_code: 0,
+                    body_len: body.len() as u32,
+                },
+                body: Bytes::from_static(body),
+            };
+            reply_tx.send(Ok(frame)).await.unwrap();
+        }
+    }
+
+    #[async_trait]
+    impl ClientChannel for Calls {
+        async fn call_method(&self, method_id: u32) -> Result<ClientReaderWriter, ClientError> {
+            let (request_tx, request_rx) = mpsc::channel(8);
+            let (reply_tx, reply_rx) = mpsc::channel(8);
+            let mut calls = self.0.lock().unwrap();
+            let rw = ClientReaderWriter::new(request_tx, reply_rx, calls.len() as u32, method_id);
+            calls.push((request_rx, reply_tx));
+            Ok(rw)
+        }
+    }
+
+    fn hedged(calls: &Calls, budget: RetryBudget) -> RetryChannel<Calls> {
+        let policy = HedgePolicy {
+            max_attempts: 3,
+            delay: Duration::from_millis(5),
+            non_fatal: vec![Code::Unavailable],
+        };
+        RetryChannel::new(calls.clone(), RetryPolicy::default())
+            .hedge(policy)
+            .budget(budget)
+    }
+
+    fn idempotent_call() -> UnaryCall {
+        UnaryCall {
+            idempotent: true,
+            ..UnaryCall::new(0, Bytes::from_static(b"request"))
+        }
+    }
+
+    #[tokio::test]
+    async fn hedge_within_budget() {
+        let calls = Calls::default();
+        let budget = RetryBudget::new(2.0, 0.1);
+        budget.on_failure();
+        budget.on_failure();
+        let channel = hedged(&calls, budget);
+
+        let call = idempotent_call();
+        let server = async {
+            time::sleep(Duration::from_millis(50)).await;
+            // out of budget, no attempt after the first
+            assert_eq!(calls.len(), 1);
+            calls.reply(0, b"reply").await;
+        };
+        let (reply, ()) = tokio::join!(channel.unary(&call), server);
+        assert_eq!(reply.unwrap(), (0, Bytes::from_static(b"reply")));
+        assert_eq!(calls.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn losing_hedge_is_cancelled() {
+        let calls = Calls::default();
+        let channel = hedged(&calls, RetryBudget::default());
+
+        let call = idempotent_call();
+        let server = async {
+            time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(calls.len(), 3);
+            calls.reply(1, b"second").await;
+        };
+        let (reply, ()) = tokio::join!(channel.unary(&call), server);
+        assert_eq!(reply.unwrap(), (0, Bytes::from_static(b"second")));
+
+        let mut opened = calls.0.lock().unwrap();
+        for (n, (request_rx, _)) in opened.iter_mut().enumerate() {
+            let mut last = None;
+            while let Ok(frame) = request_rx.try_recv() {
+                last = Some(frame.header.flag);
+            }
+            let last = last.unwrap();
+            assert_eq!(last.is(RequestFlagBit::CANCEL), n != 1, "attempt {}", n);
+        }
+    }
+
+    #[test]
+    fn budget_stops_retries() {
+        let budget = RetryBudget::new(4.0, 0.5);
+        assert!(budget.allow());
+        budget.on_failure();
+        assert!(budget.allow());
+        budget.on_failure();
+        assert!(!budget.allow());
+        budget.on_success();
+        assert!(budget.allow());
+    }
+}
@@ -1,16 +1,164 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
 use bytes::Bytes;
+use futures::{future::poll_fn, pin_mut, ready, Sink, Stream, StreamExt};
 use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
 
-use crate::protocol::frame::*;
+use crate::{
+    codec::Codec,
+    protocol::{frame::*, Metadata, ReadEvent, Status, StreamState},
+};
 
-use super::ClientError;
+use super::{
+    channel::{OpenRequest, ReplyResult},
+    interceptor::{CallObserver, InterceptorChain},
+    ClientChannel, ClientError, ReplyStream, RequestSink,
+};
 
 pub trait ClientStub {
-    fn channel(&self) -> &'_ crate::client::RunningChannel;
+    fn channel(&self) -> &'_ dyn ClientChannel;
 
     fn first_method_id(&self) -> u32;
 }
 
+/// An encoded unary call, kept whole so a channel may send it again.
+#[derive(Debug, Clone)]
+pub struct UnaryCall {
+    pub method_id: u32,
+    pub metadata: Option<Metadata>,
+    pub request: Bytes,
+    // safe to send more than once
+    pub idempotent: bool,
+}
+
+impl UnaryCall {
+    pub fn new(method_id: u32, request: Bytes) -> Self {
+        Self {
+            method_id,
+            metadata: None,
+            request,
+            idempotent: false,
+        }
+    }
+
+    /// Send the call on `rw` and read the only reply.
+    pub async fn send(&self, mut rw: ClientReaderWriter) -> Result<(u32, Bytes), ClientError> {
+        self.write(&mut rw).await?;
+        rw.read_unary().await
+    }
+
+    /// Write the metadata and the request, ending the request side.
+    pub async fn write(&self, rw: &mut ClientReaderWriter) -> Result<(), ClientError> {
+        if let Some(metadata) = &self.metadata {
+            rw.write_metadata(metadata).await?;
+        }
+        rw.write_last(self.request.clone()).await
+    }
+}
+
+/// Send one encoded request through `channel` and decode the single reply,
+/// a non-zero status reply becomes `ClientError::Status`.
+pub async fn call_unary<C, Req, Resp>(
+    channel: &dyn ClientChannel,
+    method_id: u32,
+    request: &Req,
+    idempotent: bool,
+) -> Result<Resp, ClientError>
+where
+    C: Codec<Req> + Codec<Resp>,
+{
+    let call = UnaryCall {
+        method_id,
+        metadata: Some(content_type::<C>()),
+        request: <C as Codec<Req>>::encode(request)?,
+        idempotent,
+    };
+    let (status_code, body) = channel.unary(&call).await?;
+    decode_reply::<C, Resp>(status_code, body)
+}
+
+/// Send every message of `requests`, end the request stream and decode
+/// the single reply.
+pub async fn call_client_stream<C, Req, Resp>(
+    mut rw: ClientReaderWriter,
+    requests: impl Stream<Item = Req>,
+) -> Result<Resp, ClientError>
+where
+    C: Codec<Req> + Codec<Resp>,
+{
+    rw.write_metadata(&content_type::<C>()).await?;
+    pin_mut!(requests);
+    while let Some(request) = requests.next().await {
+        rw.write(<C as Codec<Req>>::encode(&request)?).await?;
+    }
+    rw.write_complete().await?;
+    read_reply::<C, Resp>(&mut rw).await
+}
+
+/// Send one request and stream the decoded replies.
+pub async fn call_server_stream<C, Req, Resp>(
+    mut rw: ClientReaderWriter,
+    request: &Req,
+) -> Result<ReplyStream<Resp, C>, ClientError>
+where
+    C: Codec<Req> + Codec<Resp>,
+{
+    rw.write_metadata(&content_type::<C>()).await?;
+    rw.write_last(<C as Codec<Req>>::encode(request)?).await?;
+    let (reader, _) = rw.split();
+    Ok(ReplyStream::new(reader))
+}
+
+/// Stream the decoded replies while `requests` is sent, the requests are
+/// written as the reply stream is polled.
+///
+/// Unlike `call_client_stream`, which sends every request before it
+/// returns and so may borrow `requests`, the returned stream outlives this
+/// call and owns the sending half. `requests` is therefore `'static`, and
+/// `Send` so the `ReplyStream` can move between threads, e.g. behind a
+/// `BlockingStream`.
+pub async fn call_bidi<C, Req, Resp>(
+    mut rw: ClientReaderWriter,
+    requests: impl Stream<Item = Req> + Send + 'static,
+) -> Result<ReplyStream<Resp, C>, ClientError>
+where
+    C: Codec<Req> + Codec<Resp> + 'static,
+    Req: Send + 'static,
+{
+    rw.write_metadata(&content_type::<C>()).await?;
+    let (reader, writer) = rw.split();
+    let sending = requests.map(Ok).forward(RequestSink::<Req, C>::new(writer));
+    Ok(ReplyStream::with_sending(reader, Box::pin(sending)))
+}
+
+fn content_type<C: crate::codec::ContentType>() -> Metadata {
+    let mut metadata = Metadata::new();
+    metadata.set_content_type(C::CONTENT_TYPE);
+    metadata
+}
+
+async fn read_reply<C, Resp>(rw: &mut ClientReaderWriter) -> Result<Resp, ClientError>
+where
+    C: Codec<Resp>,
+{
+    let (status_code, body) = rw.read_unary().await?;
+    decode_reply::<C, Resp>(status_code, body)
+}
+
+fn decode_reply<C, Resp>(status_code: u32, body: Bytes) -> Result<Resp, ClientError>
+where
+    C: Codec<Resp>,
+{
+    if status_code != 0 {
+        return Err(Status::from_frame(status_code, &body).into());
+    }
+    Ok(C::decode(body)?)
+}
+
 pub struct ClientReaderWriter {
     writer: ClientWriter,
     reader: ClientReader,
@@ -19,7 +167,7 @@ pub struct ClientReaderWriter {
 impl ClientReaderWriter {
     pub fn new(
         writer_chan: mpsc::Sender<RequestFrame>,
-        reader_chan: mpsc::Receiver<ReplyFrame>,
+        reader_chan: mpsc::Receiver<ReplyResult>,
         request_id: u32,
         method_id: u32,
     ) -> Self {
@@ -29,6 +177,14 @@ impl ClientReaderWriter {
         }
     }
 
+    pub fn request_id(&self) -> u32 {
+        self.writer.request_id
+    }
+
+    pub async fn write_metadata(&mut self, metadata: &Metadata) -> Result<(), ClientError> {
+        self.writer.write_metadata(metadata).await
+    }
+
     pub async fn write(&mut self, reply_body: Bytes) -> Result<(), ClientError> {
         self.writer.write(reply_body).await
     }
@@ -37,98 +193,396 @@ impl ClientReaderWriter {
         self.writer.write_last(reply_body).await
     }
 
+    /// End the request stream, replies can still be read.
     pub async fn write_complete(&mut self) -> Result<(), ClientError> {
         self.writer.write_complete().await
     }
 
-    pub async fn read(&mut self) -> Option<(u32, Bytes)> {
+    pub async fn read(&mut self) -> Result<Option<(u32, Bytes)>, ClientError> {
         self.reader.read().await
     }
 
+    pub async fn read_event(&mut self) -> Result<ReadEvent<(u32, Bytes)>, ClientError> {
+        self.reader.read_event().await
+    }
+
+    /// Read the only reply of a unary call, a second message is an error.
+    pub async fn read_unary(&mut self) -> Result<(u32, Bytes), ClientError> {
+        self.reader.read_unary().await
+    }
+
+    /// Tell the server to stop the call, see `ClientWriter::cancel`.
+    pub async fn cancel(mut self) -> Result<(), ClientError> {
+        self.writer.cancel().await
+    }
+
     pub fn split(self) -> (ClientReader, ClientWriter) {
         (self.reader, self.writer)
     }
+
+    pub(crate) fn hold_request_id(&mut self, open: OpenRequest) {
+        self.writer.open = Some(open);
+    }
+
+    /// Run `chain` on the frames of this call, before a chain already set.
+    pub(crate) fn intercept(&mut self, chain: &InterceptorChain) {
+        let chain = match &self.writer.observer {
+            Some(observer) => chain.chain(&observer.chain),
+            None => chain.clone(),
+        };
+        let observer = CallObserver {
+            method_id: self.writer.method_id,
+            chain,
+        };
+        self.reader.observer = Some(observer.clone());
+        self.writer.observer = Some(observer);
+    }
+
+    /// Encode requests from `Req` and decode replies as `Reply` with `C`.
+    pub fn typed<Req, Reply, C>(self) -> (RequestSink<Req, C>, ReplyStream<Reply, C>)
+    where
+        C: Codec<Req> + Codec<Reply>,
+    {
+        (RequestSink::new(self.writer), ReplyStream::new(self.reader))
+    }
 }
 
+/// Also a `Stream` of replies, it ends with the reply stream.
 pub struct ClientReader {
-    reader_chan: mpsc::Receiver<ReplyFrame>,
+    reader_chan: mpsc::Receiver<ReplyResult>,
+    observer: Option<CallObserver>,
+    // the server's EOS or an error was read, nothing follows
+    ended: bool,
 }
 
 impl ClientReader {
-    pub fn new(reader_chan: mpsc::Receiver<ReplyFrame>) -> Self {
-        Self { reader_chan }
+    pub fn new(reader_chan: mpsc::Receiver<ReplyResult>) -> Self {
+        Self {
+            reader_chan,
+            observer: None,
+            ended: false,
+        }
+    }
+
+    /// `Ok(None)` once the server ended the call, `Err(ConnectionLost)` if
+    /// the connection died first.
+    pub async fn read(&mut self) -> Result<Option<(u32, Bytes)>, ClientError> {
+        poll_fn(|cx| self.poll_read(cx)).await
     }
 
-    pub async fn read(&mut self) -> Option<(u32, Bytes)> {
-        let frame = self.reader_chan.recv().await;
-        frame.map(|frame| (frame.header.status_code, frame.body))
+    pub async fn read_event(&mut self) -> Result<ReadEvent<(u32, Bytes)>, ClientError> {
+        poll_fn(|cx| self.poll_read_event(cx)).await
+    }
+
+    /// Read the only reply of a unary call, a second message is an error.
+    pub async fn read_unary(&mut self) -> Result<(u32, Bytes), ClientError> {
+        let reply = self.read().await?.ok_or(ClientError::NoReply())?;
+        if reply.0 == 0 && self.read().await?.is_some() {
+            return Err(ClientError::UnexpectedReply());
+        }
+        Ok(reply)
+    }
+
+    /// A status reply is read as a message, `poll_read_event` reads it as
+    /// a reset.
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<(u32, Bytes)>, ClientError>> {
+        self.poll_frame(cx)
+            .map_ok(|frame| frame.map(|frame| (frame.header.status_code, frame.body)))
+    }
+
+    /// `EndOfStream` once the server ended the reply stream with OK,
+    /// `Reset` with the status it ended it with otherwise.
+    pub fn poll_read_event(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<ReadEvent<(u32, Bytes)>, ClientError>> {
+        use ReplyFlagBit::*;
+        self.poll_frame(cx).map_ok(|frame| match frame {
+            Some(frame) if frame.header.flag.is(SIGNAL) => {
+                let status = Status::from_frame(frame.header.status_code, &frame.body);
+                ReadEvent::Reset(status)
+            }
+            Some(frame) => ReadEvent::Message((frame.header.status_code, frame.body)),
+            None => ReadEvent::EndOfStream,
+        })
+    }
+
+    /// The next reply frame, the channel closing before the server's `EOS`
+    /// is a lost connection.
+    fn poll_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<ReplyFrame>, ClientError>> {
+        use ReplyFlagBit::*;
+        if self.ended {
+            return Poll::Ready(Ok(None));
+        }
+        let frame = match ready!(self.reader_chan.poll_recv(cx)) {
+            Some(Ok(frame)) => frame,
+            r => {
+                self.ended = true;
+                let e = match r {
+                    Some(Err(e)) => e,
+                    _ => ClientError::ConnectionLost(),
+                };
+                if let Some(CallObserver { method_id, chain }) = &self.observer {
+                    chain.on_error(*method_id, &e);
+                }
+                return Poll::Ready(Err(e));
+            }
+        };
+        let flag = frame.header.flag;
+        self.ended = flag.is(EOS);
+        // an OK `EOS | SIGNAL` frame only ends the stream
+        if flag.is(SIGNAL) && frame.header.status_code == 0 {
+            return Poll::Ready(Ok(None));
+        }
+        if let Some(CallObserver { method_id, chain }) = &self.observer {
+            chain.on_reply(*method_id, frame.header.status_code, &frame.body);
+        }
+        Poll::Ready(Ok(Some(frame)))
+    }
+}
+
+impl Stream for ClientReader {
+    type Item = Result<(u32, Bytes), ClientError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_read(cx).map(Result::transpose)
     }
 }
 
+/// Also a `Sink` of request messages, closing it ends the request stream.
 pub struct ClientWriter {
     writer_chan: mpsc::Sender<RequestFrame>,
-    have_write: bool,
+    sink: PollSender<RequestFrame>,
+    // the request half, the reply half is followed by the channel
+    state: StreamState,
     request_id: u32,
     method_id: u32,
+    observer: Option<CallObserver>,
+    // the observer's metadata was sent or there was none
+    metadata_done: bool,
+    // the observer's metadata, built once, waiting for a send slot
+    pending_metadata: Option<Metadata>,
+    // released once the request half ends
+    open: Option<OpenRequest>,
 }
 
 impl ClientWriter {
     pub fn new(writer_chan: mpsc::Sender<RequestFrame>, request_id: u32, method_id: u32) -> Self {
         Self {
+            sink: PollSender::new(writer_chan.clone()),
             writer_chan,
-            have_write: false,
+            state: StreamState::Idle,
             request_id,
             method_id,
+            observer: None,
+            metadata_done: false,
+            pending_metadata: None,
+            open: None,
+        }
+    }
+
+    /// Send call metadata, must be the first frame of the call.
+    pub async fn write_metadata(&mut self, metadata: &Metadata) -> Result<(), ClientError> {
+        use RequestFlagBit::*;
+        if self.state != StreamState::Idle {
+            return Err(ClientError::MetadataAfterWrite());
         }
+        let body = match &self.observer {
+            Some(CallObserver { method_id, chain }) => {
+                let mut metadata = metadata.clone();
+                chain.on_metadata(*method_id, &mut metadata);
+                metadata.encode()
+            }
+            None => metadata.encode(),
+        };
+        self.metadata_done = true;
+        let msg = self.frame(RequestFlag::default().set(METADATA), body)?;
+        self.write_msg(msg).await
     }
 
     pub async fn write(&mut self, request_body: Bytes) -> Result<(), ClientError> {
-        self.write_msg(RequestFrame {
-            header: RequestHeader {
-                request_id: self.request_id,
-                flag: RequestFlag::default(),
-                method_id: self.method_id,
-                body_len: request_body.len() as u32,
-            },
-            body: request_body,
-        })
-        .await
+        poll_fn(|cx| self.poll_observer_metadata(cx)).await?;
+        let msg = self.frame(RequestFlag::default(), request_body)?;
+        self.write_msg(msg).await
     }
 
     pub async fn write_last(&mut self, request_body: Bytes) -> Result<(), ClientError> {
         use RequestFlagBit::*;
-        self.write_msg(RequestFrame {
+        poll_fn(|cx| self.poll_observer_metadata(cx)).await?;
+        let msg = self.frame(RequestFlag::default().set(EOS), request_body)?;
+        self.write_msg(msg).await
+    }
+
+    pub async fn write_complete(&mut self) -> Result<(), ClientError> {
+        use RequestFlagBit::*;
+        poll_fn(|cx| self.poll_observer_metadata(cx)).await?;
+        let msg = self.frame(RequestFlag::default().set(EOS).set(SIGNAL), Bytes::new())?;
+        self.write_msg(msg).await
+    }
+
+    /// Send the observer's metadata ahead of the first frame if the caller
+    /// wrote none.
+    fn poll_observer_metadata(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ClientError>> {
+        use RequestFlagBit::*;
+        if self.metadata_done || self.state != StreamState::Idle {
+            return Poll::Ready(Ok(()));
+        }
+        // the interceptors run once, however often this is polled
+        let metadata = match (self.pending_metadata.take(), &self.observer) {
+            (Some(metadata), _) => metadata,
+            (None, Some(CallObserver { method_id, chain })) => {
+                let mut metadata = Metadata::new();
+                chain.on_metadata(*method_id, &mut metadata);
+                metadata
+            }
+            (None, None) => Metadata::new(),
+        };
+        if metadata.is_empty() {
+            self.metadata_done = true;
+            return Poll::Ready(Ok(()));
+        }
+        match self.sink.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(ClientError::ConnectionLost())),
+            Poll::Pending => {
+                self.pending_metadata = Some(metadata);
+                return Poll::Pending;
+            }
+        }
+        self.metadata_done = true;
+        let msg = self.frame(RequestFlag::default().set(METADATA), metadata.encode())?;
+        self.sink
+            .send_item(msg)
+            .map_err(|_| ClientError::ConnectionLost())?;
+        Poll::Ready(Ok(()))
+    }
+
+    pub fn is_ended(&self) -> bool {
+        !self.state.can_send()
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.state
+    }
+
+    /// Abandon the call, also after the request stream ended. The server
+    /// stops the method and ends the call with `CANCELLED`.
+    pub async fn cancel(&mut self) -> Result<(), ClientError> {
+        use RequestFlagBit::*;
+        let mut flag = RequestFlag::default().set(EOS).set(SIGNAL).set(CANCEL);
+        if self.state == StreamState::Idle {
+            flag.set_in_place(FIRST);
+        }
+        self.state = self.state.reset();
+        self.open = None;
+        let msg = RequestFrame {
             header: RequestHeader {
                 request_id: self.request_id,
-                flag: RequestFlag::default().set(EOS),
+                flag,
                 method_id: self.method_id,
-                body_len: request_body.len() as u32,
+                body_len: 0,
             },
-            body: request_body,
-        })
-        .await
+            body: Bytes::new(),
+        };
+        self.write_msg(msg).await
     }
 
-    pub async fn write_complete(&mut self) -> Result<(), ClientError> {
+    /// Build the next request frame, the first one carries FIRST.
+    fn frame(&mut self, mut flag: RequestFlag, body: Bytes) -> Result<RequestFrame, ClientError> {
         use RequestFlagBit::*;
-        self.write_msg(RequestFrame {
+        let empty_end = flag.is(EOS) && flag.is(SIGNAL);
+        let state = self.state.send(flag.is(EOS), empty_end)?;
+        if self.state == StreamState::Idle {
+            flag.set_in_place(FIRST);
+        }
+        self.state = state;
+        if !state.can_send() {
+            self.open = None;
+        }
+        if !flag.is(METADATA) && !flag.is(SIGNAL) {
+            if let Some(CallObserver { method_id, chain }) = &self.observer {
+                chain.on_request(*method_id, &body);
+            }
+        }
+        Ok(RequestFrame {
             header: RequestHeader {
                 request_id: self.request_id,
-                flag: RequestFlag::default().set(EOS).set(SIGNAL),
+                flag,
                 method_id: self.method_id,
-                body_len: 0,
+                body_len: body.len() as u32,
             },
-            body: Bytes::new(),
+            body,
         })
-        .await
     }
 
-    async fn write_msg(&mut self, mut msg: RequestFrame) -> Result<(), ClientError> {
+    async fn write_msg(&mut self, msg: RequestFrame) -> Result<(), ClientError> {
+        Ok(self.writer_chan.send(msg).await?)
+    }
+}
+
+impl Sink<Bytes> for ClientWriter {
+    type Error = ClientError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ClientError>> {
+        let this = self.get_mut();
+        ready!(this.poll_observer_metadata(cx))?;
+        this.sink
+            .poll_reserve(cx)
+            .map_err(|_| ClientError::ConnectionLost())
+    }
+
+    fn start_send(self: Pin<&mut Self>, request_body: Bytes) -> Result<(), ClientError> {
+        let this = self.get_mut();
+        let msg = this.frame(RequestFlag::default(), request_body)?;
+        this.sink
+            .send_item(msg)
+            .map_err(|_| ClientError::ConnectionLost())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ClientError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ClientError>> {
         use RequestFlagBit::*;
-        if !self.have_write {
-            msg.header.flag.set_in_place(FIRST);
-            self.have_write = true;
+        let this = self.get_mut();
+        if !this.state.can_send() {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(this.poll_observer_metadata(cx))?;
+        ready!(this.sink.poll_reserve(cx)).map_err(|_| ClientError::ConnectionLost())?;
+        let msg = this.frame(RequestFlag::default().set(EOS).set(SIGNAL), Bytes::new())?;
+        this.sink
+            .send_item(msg)
+            .map_err(|_| ClientError::ConnectionLost())?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Dropping a writer whose request stream is open ends it, the replies can
+/// still be read. A writer dropped before its first frame gives up the call.
+impl Drop for ClientWriter {
+    fn drop(&mut self) {
+        use RequestFlagBit::*;
+        if self.state == StreamState::Idle {
+            if let Some(open) = self.open.take() {
+                open.abandon();
+            }
+            return;
+        }
+        if !self.state.can_send() {
+            return;
+        }
+        if let Ok(msg) = self.frame(RequestFlag::default().set(EOS).set(SIGNAL), Bytes::new()) {
+            // with a full request buffer the server keeps the stream open
+            // until the connection closes
+            let _ = self.writer_chan.try_send(msg);
         }
-        Ok(self.writer_chan.send(msg).await?)
     }
 }
@@ -0,0 +1,122 @@
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{future::BoxFuture, ready, FutureExt, Sink, SinkExt, Stream};
+
+use crate::{
+    codec::{Codec, ProstCodec},
+    protocol::Status,
+};
+
+use super::{
+    service::{ClientReader, ClientWriter},
+    ClientError,
+};
+
+/// Decoded replies of a `server_stream` or `bidi` call.
+///
+/// A non-zero status ends the stream with `ClientError::Status`.
+pub struct ReplyStream<T, C = ProstCodec> {
+    reader: ClientReader,
+    // writes the requests of a bidi call, driven by `poll_next`
+    sending: Option<BoxFuture<'static, Result<(), ClientError>>>,
+    done: bool,
+    _codec: PhantomData<fn() -> (T, C)>,
+}
+
+impl<T, C: Codec<T>> ReplyStream<T, C> {
+    pub fn new(reader: ClientReader) -> Self {
+        Self {
+            reader,
+            sending: None,
+            done: false,
+            _codec: PhantomData,
+        }
+    }
+
+    pub fn with_sending(
+        reader: ClientReader,
+        sending: BoxFuture<'static, Result<(), ClientError>>,
+    ) -> Self {
+        Self {
+            sending: Some(sending),
+            ..Self::new(reader)
+        }
+    }
+}
+
+impl<T, C: Codec<T>> Stream for ReplyStream<T, C> {
+    type Item = Result<T, ClientError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        if let Some(sending) = &mut this.sending {
+            if let Poll::Ready(r) = sending.poll_unpin(cx) {
+                this.sending = None;
+                if let Err(e) = r {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+        let reply = match ready!(this.reader.poll_read(cx)) {
+            Ok(Some((0, body))) => C::decode(body).map_err(ClientError::from),
+            Ok(Some((status_code, body))) => Err(Status::from_frame(status_code, &body).into()),
+            Ok(None) => {
+                this.done = true;
+                return Poll::Ready(None);
+            }
+            Err(e) => Err(e),
+        };
+        if reply.is_err() {
+            this.done = true;
+        }
+        Poll::Ready(Some(reply))
+    }
+}
+
+/// Encoding request sink, closing it ends the request stream.
+pub struct RequestSink<T, C = ProstCodec> {
+    writer: ClientWriter,
+    _codec: PhantomData<fn(T) -> C>,
+}
+
+impl<T, C: Codec<T>> RequestSink<T, C> {
+    pub fn new(writer: ClientWriter) -> Self {
+        Self {
+            writer,
+            _codec: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> ClientWriter {
+        self.writer
+    }
+}
+
+impl<T, C: Codec<T>> Sink<T> for RequestSink<T, C> {
+    type Error = ClientError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ClientError>> {
+        self.get_mut().writer.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), ClientError> {
+        let body = C::encode(&item)?;
+        self.get_mut().writer.start_send_unpin(body)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ClientError>> {
+        self.get_mut().writer.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ClientError>> {
+        self.get_mut().writer.poll_close_unpin(cx)
+    }
+}
@@ -0,0 +1,115 @@
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+
+use crate::protocol::Status;
+
+use super::{ClientError, RunningChannel};
+
+/// A unary method exposed as a `tower::Service`, so `tower::ServiceBuilder`
+/// layers can wrap rspc calls.
+///
+/// A non-zero status is a `ClientError::Status`, so retry, timeout and
+/// load-shed layers see it as a failure.
+#[derive(Clone)]
+pub struct UnaryService {
+    channel: RunningChannel,
+    method_id: u32,
+}
+
+impl UnaryService {
+    pub fn new(channel: RunningChannel, method_id: u32) -> Self {
+        Self { channel, method_id }
+    }
+}
+
+impl tower::Service<Bytes> for UnaryService {
+    type Response = Bytes;
+    type Error = ClientError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Bytes) -> Self::Future {
+        let rw = self.channel.call_method(self.method_id);
+        Box::pin(async move {
+            let mut rw = rw?;
+            rw.write_last(request).await?;
+            match rw.read_unary().await? {
+                (0, body) => Ok(body),
+                (status_code, body) => Err(Status::from_frame(status_code, &body).into()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
+    use tokio::{net::TcpListener, task};
+    use tower::{service_fn, BoxError, Service, ServiceBuilder, ServiceExt};
+
+    use super::*;
+    use crate::{
+        client::Channel,
+        protocol::Code,
+        server::{self, service::ServiceTable, TowerRequest, TowerService},
+    };
+
+    #[tokio::test]
+    async fn round_trip_through_layers() {
+        task::LocalSet::new()
+            .run_until(async {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let echo = service_fn(|request: TowerRequest| async move {
+                    match &request.body[..] {
+                        b"missing" => Err(Status::not_found("missing")),
+                        _ => Ok((0, request.body)),
+                    }
+                });
+                let mut table = ServiceTable::new();
+                table.register_service(TowerService::new("Echo", &["echo"], echo));
+                task::spawn_local(async move {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mut channel = server::Channel::new(stream, Rc::new(RefCell::new(table)));
+                    let _ = channel.run().await;
+                });
+
+                let (run, channel) = Channel::new(addr).await.unwrap().run();
+                task::spawn_local(run);
+                let mut service = ServiceBuilder::new()
+                    .concurrency_limit(2)
+                    .timeout(Duration::from_secs(5))
+                    .service(UnaryService::new(channel, 0));
+
+                for body in [&b"one"[..], b"two"] {
+                    let reply = service
+                        .ready()
+                        .await
+                        .unwrap()
+                        .call(Bytes::from_static(body))
+                        .await
+                        .unwrap();
+                    assert_eq!(reply, body);
+                }
+
+                let e: BoxError = service
+                    .ready()
+                    .await
+                    .unwrap()
+                    .call(Bytes::from_static(b"missing"))
+                    .await
+                    .unwrap_err();
+                match e.downcast::<ClientError>().map(|e| *e) {
+                    Ok(ClientError::Status(status)) => assert_eq!(status.code(), Code::NotFound),
+                    other => panic!("unexpected reply: {:?}", other),
+                }
+            })
+            .await;
+    }
+}
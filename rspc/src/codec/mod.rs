@@ -0,0 +1,41 @@
+use bytes::Bytes;
+
+use crate::protocol::Status;
+
+pub mod prost;
+pub mod serde;
+
+pub use self::prost::ProstCodec;
+pub use self::serde::{BincodeCodec, CborCodec, JsonCodec, MsgPackCodec};
+
+/// Name of the wire format, sent as `content-type` call metadata.
+pub trait ContentType {
+    const CONTENT_TYPE: &'static str;
+}
+
+/// Turns typed messages into frame bodies and back.
+///
+/// Decoding a request that does not parse should fail with
+/// `Code::InvalidArgument`, so the peer sees what went wrong.
+pub trait Codec<T>: ContentType {
+    fn encode(item: &T) -> Result<Bytes, Status>;
+
+    fn decode(buf: Bytes) -> Result<T, Status>;
+}
+
+/// Pass frame bodies through untouched.
+pub struct BytesCodec;
+
+impl ContentType for BytesCodec {
+    const CONTENT_TYPE: &'static str = "application/octet-stream";
+}
+
+impl Codec<Bytes> for BytesCodec {
+    fn encode(item: &Bytes) -> Result<Bytes, Status> {
+        Ok(item.clone())
+    }
+
+    fn decode(buf: Bytes) -> Result<Bytes, Status> {
+        Ok(buf)
+    }
+}
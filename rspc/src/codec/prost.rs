@@ -0,0 +1,44 @@
+use bytes::Bytes;
+
+use crate::protocol::Status;
+
+use super::{Codec, ContentType};
+
+pub struct ProstCodec;
+
+impl ContentType for ProstCodec {
+    const CONTENT_TYPE: &'static str = "application/protobuf";
+}
+
+impl<T> Codec<T> for ProstCodec
+where
+    T: prost::Message + Default,
+{
+    fn encode(item: &T) -> Result<Bytes, Status> {
+        Ok(item.encode_to_vec().into())
+    }
+
+    fn decode(buf: Bytes) -> Result<T, Status> {
+        T::decode(buf).map_err(|e| Status::invalid_argument(format!("decode message: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{example::pb::HelloRequest, protocol::Code};
+
+    #[test]
+    fn prost_codec_roundtrip_and_invalid_argument() {
+        let request = HelloRequest {
+            name: "rspc".into(),
+        };
+        let body = <ProstCodec as Codec<HelloRequest>>::encode(&request).unwrap();
+        let decoded: HelloRequest = <ProstCodec as Codec<HelloRequest>>::decode(body).unwrap();
+        assert_eq!(decoded, request);
+
+        let err = <ProstCodec as Codec<HelloRequest>>::decode(Bytes::from_static(&[0x0a, 0x05]))
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,135 @@
+use bytes::{Buf, Bytes};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::protocol::Status;
+
+use super::{Codec, ContentType};
+
+fn encode_error(e: impl std::fmt::Display) -> Status {
+    Status::internal(format!("encode message: {}", e))
+}
+
+fn decode_error(e: impl std::fmt::Display) -> Status {
+    Status::invalid_argument(format!("decode message: {}", e))
+}
+
+pub struct JsonCodec;
+
+impl ContentType for JsonCodec {
+    const CONTENT_TYPE: &'static str = "application/json";
+}
+
+impl<T> Codec<T> for JsonCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(item: &T) -> Result<Bytes, Status> {
+        serde_json::to_vec(item)
+            .map(Bytes::from)
+            .map_err(encode_error)
+    }
+
+    fn decode(buf: Bytes) -> Result<T, Status> {
+        serde_json::from_slice(&buf).map_err(decode_error)
+    }
+}
+
+pub struct BincodeCodec;
+
+impl ContentType for BincodeCodec {
+    const CONTENT_TYPE: &'static str = "application/x-bincode";
+}
+
+impl<T> Codec<T> for BincodeCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(item: &T) -> Result<Bytes, Status> {
+        bincode::serialize(item)
+            .map(Bytes::from)
+            .map_err(encode_error)
+    }
+
+    fn decode(buf: Bytes) -> Result<T, Status> {
+        bincode::deserialize(&buf).map_err(decode_error)
+    }
+}
+
+pub struct MsgPackCodec;
+
+impl ContentType for MsgPackCodec {
+    const CONTENT_TYPE: &'static str = "application/msgpack";
+}
+
+impl<T> Codec<T> for MsgPackCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(item: &T) -> Result<Bytes, Status> {
+        // named fields, so peers may add or reorder struct fields
+        rmp_serde::to_vec_named(item)
+            .map(Bytes::from)
+            .map_err(encode_error)
+    }
+
+    fn decode(buf: Bytes) -> Result<T, Status> {
+        rmp_serde::from_slice(&buf).map_err(decode_error)
+    }
+}
+
+pub struct CborCodec;
+
+impl ContentType for CborCodec {
+    const CONTENT_TYPE: &'static str = "application/cbor";
+}
+
+impl<T> Codec<T> for CborCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(item: &T) -> Result<Bytes, Status> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(item, &mut buf).map_err(encode_error)?;
+        Ok(buf.into())
+    }
+
+    fn decode(buf: Bytes) -> Result<T, Status> {
+        ciborium::de::from_reader(buf.reader()).map_err(decode_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::protocol::Code;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        id: u64,
+        items: Vec<String>,
+    }
+
+    fn roundtrip<C: Codec<Order>>() {
+        let order = Order {
+            id: 7,
+            items: vec!["tea".into(), "cake".into()],
+        };
+        assert_eq!(C::decode(C::encode(&order).unwrap()).unwrap(), order);
+        assert_eq!(
+            C::decode(Bytes::from_static(b"\xff\xff"))
+                .unwrap_err()
+                .code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn serde_codecs_roundtrip() {
+        roundtrip::<JsonCodec>();
+        roundtrip::<BincodeCodec>();
+        roundtrip::<MsgPackCodec>();
+        roundtrip::<CborCodec>();
+    }
+}
@@ -2,77 +2,71 @@ use std::cell::Cell;
 
 use crate as rspc;
 
-pub struct HelloServer {
-    share_states: Cell<i32>,
+pub mod pb {
+    use crate as rspc;
+
+    include!(concat!(env!("OUT_DIR"), "/rspc.hello.rs"));
 }
 
-#[async_trait::async_trait(?Send)]
-impl rspc::server::Service for HelloServer {
-    async fn call_method(
+/// The hello service defined once for both sides, see `rspc::service`.
+#[rspc::service(blocking)]
+pub trait Greeter {
+    #[idempotent]
+    async fn hello(
         &self,
-        fn_n: u32,
-        mut stream: rspc::server::ServerReaderWriter,
-    ) -> Result<(), rspc::server::ServerError> {
-        if fn_n < 1 {
-            if let Some(request) = stream.read().await {
-                let reply = match fn_n {
-                    0 => self.hello(request).await,
-                    _ => return Err(rspc::server::ServerError::NormalRpcMethodError()),
-                };
-
-                stream.write(reply.0, reply.1).await?;
-                Ok(())
-            } else {
-                Err(rspc::server::ServerError::NormalRpcMethodError())
-            }
-        } else {
-            match fn_n {
-                1 => self.hello_stream(stream).await,
-                _ => Err(rspc::server::ServerError::StreamRpcMethodError()),
-            }
-        }
-    }
+        request: pb::HelloRequest,
+        ctx: rspc::server::CallContext,
+    ) -> Result<pb::HelloReply, rspc::protocol::Status>;
 
-    fn service_name(&self) -> &'static str {
-        "HelloServer"
-    }
-
-    fn methods_name(&self) -> &'static [&'static str] {
-        &["hello", "hello_stream"]
-    }
+    #[bidi]
+    async fn hello_stream(
+        &self,
+        requests: rspc::server::RequestStream<pb::HelloRequest>,
+        replies: rspc::server::ReplySink<pb::HelloReply>,
+    ) -> Result<(), rspc::protocol::Status>;
+}
 
-    fn methods_len(&self) -> usize {
-        2
-    }
+#[derive(Default)]
+pub struct HelloServer {
+    share_states: Cell<i32>,
 }
 
 impl HelloServer {
     pub fn new() -> Self {
-        Self {
-            share_states: Cell::default(),
-        }
+        Self::default()
     }
+}
 
-    async fn hello(&self, request: bytes::Bytes) -> (u32, bytes::Bytes) {
-        println!("read request {:?}", request);
+#[async_trait::async_trait(?Send)]
+impl Greeter for HelloServer {
+    async fn hello(
+        &self,
+        request: pb::HelloRequest,
+        ctx: rspc::server::CallContext,
+    ) -> Result<pb::HelloReply, rspc::protocol::Status> {
         let count = self.share_states.get();
         self.share_states.set(count + 1);
-        (0, format!("{} hello reply", count).into())
+        Ok(pb::HelloReply {
+            msg: format!("{} hello {}", count, request.name),
+            time: format!("request {}", ctx.request_id()),
+        })
     }
 
     async fn hello_stream(
         &self,
-        mut stream: rspc::server::ServerReaderWriter,
-    ) -> Result<(), rspc::server::ServerError> {
-        while let Some(r) = stream.read().await {
-            println!("read request {:?}", r);
-            stream.write(0, r).await?;
-        }
-        let count = self.share_states.get();
-        self.share_states.set(count + 1);
-        stream
-            .write_last(0, format!("{} stream end", count).into())
-            .await?;
-        Ok(())
+        requests: rspc::server::RequestStream<pb::HelloRequest>,
+        replies: rspc::server::ReplySink<pb::HelloReply>,
+    ) -> Result<(), rspc::protocol::Status> {
+        use futures::StreamExt;
+
+        requests
+            .map(|request| {
+                request.map(|request| pb::HelloReply {
+                    msg: format!("echo {}", request.name),
+                    time: String::new(),
+                })
+            })
+            .forward(replies)
+            .await
     }
 }
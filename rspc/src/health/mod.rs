@@ -0,0 +1,282 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use prost::Message;
+use tokio::sync::{watch, Notify};
+
+use crate::{
+    codec::{Codec, ProstCodec},
+    server::{CallContext, ServerError, ServerReaderWriter, Service},
+};
+
+pub mod pb {
+    include!(concat!(env!("OUT_DIR"), "/rspc.health.v1.rs"));
+}
+
+pub use pb::health_check_response::ServingStatus;
+
+/// Name used to report the health of the whole server.
+pub const SERVER_HEALTH: &str = "";
+
+type StatusTable = Rc<RefCell<HashMap<String, watch::Sender<ServingStatus>>>>;
+
+/// Create a health service and the reporter controlling it.
+///
+/// The whole server (`SERVER_HEALTH`) starts as `Serving`, other services
+/// are `ServiceUnknown` until the application reports them.
+pub fn health_reporter() -> (HealthReporter, HealthService) {
+    let statuses = StatusTable::default();
+    let added = Rc::new(Notify::new());
+    let reporter = HealthReporter {
+        statuses: statuses.clone(),
+        added: added.clone(),
+    };
+    reporter.set_serving(SERVER_HEALTH);
+    (reporter, HealthService { statuses, added })
+}
+
+#[derive(Clone)]
+pub struct HealthReporter {
+    statuses: StatusTable,
+    // wakes watchers of services not reported yet
+    added: Rc<Notify>,
+}
+
+impl HealthReporter {
+    pub fn set_service_status(&self, service: &str, status: ServingStatus) {
+        let mut statuses = self.statuses.borrow_mut();
+        match statuses.get(service) {
+            Some(tx) => {
+                tx.send_replace(status);
+            }
+            None => {
+                statuses.insert(service.to_string(), watch::channel(status).0);
+                self.added.notify_waiters();
+            }
+        }
+    }
+
+    pub fn set_serving(&self, service: &str) {
+        self.set_service_status(service, ServingStatus::Serving);
+    }
+
+    pub fn set_not_serving(&self, service: &str) {
+        self.set_service_status(service, ServingStatus::NotServing);
+    }
+
+    /// Forget a service, its watchers see `ServiceUnknown`.
+    pub fn clear_service_status(&self, service: &str) {
+        if let Some(tx) = self.statuses.borrow_mut().remove(service) {
+            tx.send_replace(ServingStatus::ServiceUnknown);
+        }
+    }
+
+    /// Mark every known service `NotServing`, call it before draining
+    /// connections so probes stop routing new traffic here.
+    pub fn shutdown(&self) {
+        for tx in self.statuses.borrow().values() {
+            tx.send_replace(ServingStatus::NotServing);
+        }
+    }
+}
+
+pub struct HealthService {
+    statuses: StatusTable,
+    added: Rc<Notify>,
+}
+
+#[async_trait(?Send)]
+impl Service for HealthService {
+    async fn call_method(
+        &self,
+        fn_n: u32,
+        _ctx: CallContext,
+        mut stream: ServerReaderWriter,
+    ) -> Result<(), ServerError> {
+        let request = match stream.read_unary().await {
+            Ok(request) => request,
+            Err(status) => return stream.write_status(&status).await,
+        };
+        let request: pb::HealthCheckRequest = match ProstCodec::decode(request) {
+            Ok(request) => request,
+            Err(status) => return stream.write_status(&status).await,
+        };
+        match fn_n {
+            0 => {
+                let status = self.check(&request.service);
+                stream.write_last(0, encode_status(status)).await
+            }
+            1 => self.watch(&request.service, stream).await,
+            _ => Err(ServerError::ErrorServiceMethodId()),
+        }
+    }
+
+    fn service_name(&self) -> &'static str {
+        "Health"
+    }
+
+    fn methods_name(&self) -> &'static [&'static str] {
+        &["check", "watch"]
+    }
+
+    fn methods_len(&self) -> usize {
+        2
+    }
+}
+
+impl HealthService {
+    fn check(&self, service: &str) -> ServingStatus {
+        self.statuses
+            .borrow()
+            .get(service)
+            .map(|tx| *tx.borrow())
+            .unwrap_or(ServingStatus::ServiceUnknown)
+    }
+
+    /// Stream every change of `service`, `ServiceUnknown` while it is not
+    /// reported. The call runs until the client goes away.
+    async fn watch(&self, service: &str, stream: ServerReaderWriter) -> Result<(), ServerError> {
+        let mut last = None;
+        loop {
+            // an unknown name is not recorded, it waits for the reporter
+            let added = self.added.notified();
+            let status_rx = self.statuses.borrow().get(service).map(|tx| tx.subscribe());
+            let mut status_rx = match status_rx {
+                Some(status_rx) => status_rx,
+                None => {
+                    let status = ServingStatus::ServiceUnknown;
+                    if last.replace(status) != Some(status) {
+                        stream.write(0, encode_status(status)).await?;
+                    }
+                    added.await;
+                    continue;
+                }
+            };
+
+            loop {
+                let status = *status_rx.borrow_and_update();
+                if last.replace(status) != Some(status) {
+                    stream.write(0, encode_status(status)).await?;
+                }
+                // cleared by the reporter
+                if status_rx.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn encode_status(status: ServingStatus) -> Bytes {
+    pb::HealthCheckResponse {
+        status: status as i32,
+    }
+    .encode_to_vec()
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{
+        protocol::{frame::*, Code, Metadata, ReplyFrame},
+        server::{Extensions, MethodInfo},
+    };
+
+    #[test]
+    fn reporter_updates_check_status() {
+        let (reporter, service) = health_reporter();
+        assert_eq!(service.check(SERVER_HEALTH), ServingStatus::Serving);
+        assert_eq!(service.check("Hello"), ServingStatus::ServiceUnknown);
+
+        reporter.set_serving("Hello");
+        assert_eq!(service.check("Hello"), ServingStatus::Serving);
+
+        reporter.shutdown();
+        assert_eq!(service.check("Hello"), ServingStatus::NotServing);
+        assert_eq!(service.check(SERVER_HEALTH), ServingStatus::NotServing);
+
+        reporter.clear_service_status("Hello");
+        assert_eq!(service.check("Hello"), ServingStatus::ServiceUnknown);
+    }
+
+    async fn next_status(reply_rx: &mut mpsc::Receiver<ReplyFrame>) -> ServingStatus {
+        let frame = reply_rx.recv().await.unwrap();
+        let reply = pb::HealthCheckResponse::decode(frame.body).unwrap();
+        reply.status()
+    }
+
+    #[tokio::test]
+    async fn watch_unknown_service() {
+        let (reporter, service) = health_reporter();
+        let (reply_tx, mut reply_rx) = mpsc::channel(8);
+        let (_request_tx, request_rx) = mpsc::channel(8);
+        let rw = ServerReaderWriter::new(reply_tx, request_rx, 0);
+
+        let replies = async {
+            assert_eq!(
+                next_status(&mut reply_rx).await,
+                ServingStatus::ServiceUnknown
+            );
+            assert!(!service.statuses.borrow().contains_key("Hello"));
+
+            reporter.set_serving("Hello");
+            assert_eq!(next_status(&mut reply_rx).await, ServingStatus::Serving);
+
+            reporter.clear_service_status("Hello");
+            assert_eq!(
+                next_status(&mut reply_rx).await,
+                ServingStatus::ServiceUnknown
+            );
+        };
+        tokio::select! {
+            r = service.watch("Hello", rw) => panic!("watch ended: {:?}", r),
+            _ = replies => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_request() {
+        let (_reporter, service) = health_reporter();
+        let (reply_tx, mut reply_rx) = mpsc::channel(8);
+        let (request_tx, request_rx) = mpsc::channel(8);
+        let rw = ServerReaderWriter::new(reply_tx, request_rx, 0);
+        let method = MethodInfo {
+            service_name: "Health",
+            method_name: "check",
+            method_id: 0,
+            request_id: 0,
+        };
+        let ctx = CallContext::new(
+            method,
+            None,
+            Metadata::default(),
+            Extensions::new(),
+            CancellationToken::new(),
+        );
+
+        let body = Bytes::from_static(b"\xff");
+        let flag = RequestFlag::default()
+            .set(RequestFlagBit::FIRST)
+            .set(RequestFlagBit::EOS);
+        let header = RequestHeader {
+            request_id: 0,
+            flag,
+            method_id: 0,
+            body_len: body.len() as u32,
+        };
+        request_tx
+            .send(RequestFrame { header, body })
+            .await
+            .unwrap();
+
+        service.call_method(0, ctx, rw).await.unwrap();
+        let reply = reply_rx.recv().await.unwrap();
+        assert_eq!(Code::from(reply.header.status_code), Code::InvalidArgument);
+        assert!(reply.header.flag.is(ReplyFlagBit::EOS));
+    }
+}
@@ -1,9 +1,13 @@
 pub mod client;
+pub mod codec;
+pub mod health;
 pub mod protocol;
 pub mod server;
 
 pub mod example;
 
+pub use rspc_macros::service;
+
 #[cfg(test)]
 mod tests {
     #[test]
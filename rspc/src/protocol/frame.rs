@@ -1,8 +1,18 @@
 use bytes::{Buf, BufMut, Bytes};
 
+use super::status::Status;
+
 pub const REQUEST_FRAME_HEADER_LEN: usize = 16;
 pub const REPLY_FRAME_HEADER_LEN: usize = 16;
 
+/// Request ids from here up are kept for connection control frames, calls
+/// never use them.
+pub const CONTROL_REQUEST_ID_MIN: u32 = u32::MAX - 255;
+
+pub fn is_control_request_id(request_id: u32) -> bool {
+    request_id >= CONTROL_REQUEST_ID_MIN
+}
+
 #[derive(Debug)]
 pub struct RequestHeader {
     pub request_id: u32,
@@ -33,6 +43,8 @@ pub enum RequestFlagBit {
     EOS = 0,
     SIGNAL = 1,
     FIRST = 2,
+    METADATA = 3,
+    CANCEL = 4,
 }
 
 pub enum ReplyFlagBit {
@@ -46,12 +58,46 @@ pub struct ReplyFrame {
     pub body: Bytes,
 }
 
+impl ReplyFrame {
+    /// A `EOS | SIGNAL` frame ending the call with `status`.
+    pub fn status(request_id: u32, status: &Status) -> Self {
+        use ReplyFlagBit::*;
+        let body = Bytes::copy_from_slice(status.message().as_bytes());
+        Self {
+            header: ReplyHeader {
+                request_id,
+                flag: ReplyFlag::default().set(EOS).set(SIGNAL),
+                status_code: status.code().into(),
+                body_len: body.len() as u32,
+            },
+            body,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RequestFrame {
     pub header: RequestHeader,
     pub body: Bytes,
 }
 
+impl RequestFrame {
+    /// A `EOS | SIGNAL | CANCEL` frame resetting a stream the client has
+    /// no call for.
+    pub fn reset(request_id: u32) -> Self {
+        use RequestFlagBit::*;
+        Self {
+            header: RequestHeader {
+                request_id,
+                flag: RequestFlag::default().set(EOS).set(SIGNAL).set(CANCEL),
+                method_id: 0,
+                body_len: 0,
+            },
+            body: Bytes::new(),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum FrameError {
     #[error("decode error")]
@@ -59,6 +105,9 @@ pub enum FrameError {
 
     #[error("encode error")]
     EncodeBufNotEnough,
+
+    #[error("metadata is not utf-8")]
+    MetadataNotUtf8,
 }
 
 pub trait FrameHeader {
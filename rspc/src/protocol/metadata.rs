@@ -0,0 +1,124 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::frame::FrameError;
+
+/// Call timeout in milliseconds, the server turns it into a deadline.
+pub const TIMEOUT_KEY: &str = "rspc-timeout";
+
+/// Wire format of the call messages, see `codec::ContentType`.
+pub const CONTENT_TYPE_KEY: &str = "content-type";
+
+/// Key-value pairs sent once per call in a METADATA request frame.
+///
+/// Encoded as `count: u32` followed by `key_len: u32, key, value_len: u32, value`
+/// for every entry.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    entries: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.entries.insert(key.into(), value.into())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.insert(TIMEOUT_KEY, timeout.as_millis().to_string());
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.get(TIMEOUT_KEY)?
+            .parse()
+            .ok()
+            .map(Duration::from_millis)
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.get(CONTENT_TYPE_KEY)
+    }
+
+    pub fn set_content_type(&mut self, content_type: &str) {
+        self.insert(CONTENT_TYPE_KEY, content_type);
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u32(self.entries.len() as u32);
+        for (k, v) in self.entries.iter() {
+            buf.put_u32(k.len() as u32);
+            buf.put_slice(k.as_bytes());
+            buf.put_u32(v.len() as u32);
+            buf.put_slice(v.as_bytes());
+        }
+        buf.freeze()
+    }
+
+    pub fn decode(mut buf: Bytes) -> Result<Self, FrameError> {
+        fn take_string(buf: &mut Bytes) -> Result<String, FrameError> {
+            if buf.remaining() < 4 {
+                return Err(FrameError::DecodeBufNotEnough);
+            }
+            let len = buf.get_u32() as usize;
+            if buf.remaining() < len {
+                return Err(FrameError::DecodeBufNotEnough);
+            }
+            String::from_utf8(buf.split_to(len).to_vec()).map_err(|_| FrameError::MetadataNotUtf8)
+        }
+
+        if buf.remaining() < 4 {
+            return Err(FrameError::DecodeBufNotEnough);
+        }
+        let count = buf.get_u32();
+        let mut entries = BTreeMap::new();
+        for _ in 0..count {
+            let k = take_string(&mut buf)?;
+            let v = take_string(&mut buf)?;
+            entries.insert(k, v);
+        }
+        Ok(Self { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_roundtrip() {
+        let mut md = Metadata::new();
+        md.insert("authorization", "Bearer token");
+        md.insert("trace-id", "42");
+        assert_eq!(Metadata::decode(md.encode()).unwrap(), md);
+        assert!(Metadata::decode(Bytes::from_static(&[0, 0, 0, 1, 0])).is_err());
+    }
+}
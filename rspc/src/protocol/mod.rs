@@ -1,3 +1,9 @@
 pub mod frame;
+pub mod metadata;
+pub mod status;
+pub mod stream;
 
 pub use frame::*;
+pub use metadata::Metadata;
+pub use status::{Code, Status};
+pub use stream::{ReadEvent, StreamError, StreamState};
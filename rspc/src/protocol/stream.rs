@@ -0,0 +1,140 @@
+use super::status::{Code, Status};
+
+/// Lifecycle of one `request_id` seen from one end, like HTTP/2 streams.
+///
+/// "local" is the end holding the state. The first frame opens the stream,
+/// an `EOS` frame closes the half of the end which sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    Idle,
+    Open,
+    // this end sent EOS, the other may still send
+    HalfClosedLocal,
+    // the other end sent EOS, this one may still send
+    HalfClosedRemote,
+    Closed,
+}
+
+/// A frame which is not allowed in the state of its stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum StreamError {
+    #[error("frame for a stream which is not open")]
+    NotOpen(),
+
+    #[error("FIRST frame for a stream which is already open")]
+    AlreadyOpen(),
+
+    #[error("stream ended before any frame opened it")]
+    EndBeforeOpen(),
+
+    #[error("send after the local side ended")]
+    SendAfterEnd(),
+
+    #[error("frame after the remote side ended")]
+    RecvAfterEnd(),
+}
+
+impl StreamState {
+    /// State after sending a frame, `empty_end` for an `EOS | SIGNAL` frame
+    /// without a message. The first frame sent from `Idle` opens the stream.
+    pub fn send(self, eos: bool, empty_end: bool) -> Result<StreamState, StreamError> {
+        use StreamState::*;
+        match self {
+            Idle if empty_end => Err(StreamError::EndBeforeOpen()),
+            Idle | Open if eos => Ok(HalfClosedLocal),
+            Idle | Open => Ok(Open),
+            HalfClosedRemote if eos => Ok(Closed),
+            HalfClosedRemote => Ok(HalfClosedRemote),
+            HalfClosedLocal | Closed => Err(StreamError::SendAfterEnd()),
+        }
+    }
+
+    /// State after receiving a frame, only a `first` frame opens an `Idle`
+    /// stream.
+    pub fn recv(self, first: bool, eos: bool) -> Result<StreamState, StreamError> {
+        use StreamState::*;
+        match self {
+            Idle if !first => Err(StreamError::NotOpen()),
+            Idle if eos => Ok(HalfClosedRemote),
+            Idle => Ok(Open),
+            _ if first => Err(StreamError::AlreadyOpen()),
+            Open if eos => Ok(HalfClosedRemote),
+            Open => Ok(Open),
+            HalfClosedLocal if eos => Ok(Closed),
+            HalfClosedLocal => Ok(HalfClosedLocal),
+            HalfClosedRemote | Closed => Err(StreamError::RecvAfterEnd()),
+        }
+    }
+
+    /// Abandon the stream, after a `CANCEL` or a reset status.
+    pub fn reset(self) -> StreamState {
+        StreamState::Closed
+    }
+
+    /// This end may still send.
+    pub fn can_send(self) -> bool {
+        matches!(
+            self,
+            StreamState::Idle | StreamState::Open | StreamState::HalfClosedRemote
+        )
+    }
+}
+
+/// What a reader of one half of a stream gets next.
+///
+/// `EndOfStream` is the clean end, the other side sent `EOS`. `Reset` ends
+/// the half without it, by a status or a lost connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadEvent<T> {
+    Message(T),
+    EndOfStream,
+    Reset(Status),
+}
+
+impl<T> ReadEvent<T> {
+    pub fn into_message(self) -> Option<T> {
+        match self {
+            ReadEvent::Message(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+/// The status a stream is reset with.
+impl From<StreamError> for Status {
+    fn from(e: StreamError) -> Self {
+        let code = match e {
+            StreamError::AlreadyOpen() => Code::AlreadyExists,
+            _ => Code::Internal,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_transitions() {
+        use StreamState::*;
+
+        // client: request stream, then the reply stream ends
+        let s = Idle.send(false, false).unwrap();
+        assert_eq!(s, Open);
+        let s = s.send(true, true).unwrap();
+        assert_eq!(s, HalfClosedLocal);
+        assert_eq!(s.send(false, false), Err(StreamError::SendAfterEnd()));
+        assert_eq!(s.recv(false, true), Ok(Closed));
+        assert_eq!(Idle.send(true, true), Err(StreamError::EndBeforeOpen()));
+
+        // server: unary request, then the reply
+        let s = Idle.recv(true, true).unwrap();
+        assert_eq!(s, HalfClosedRemote);
+        assert_eq!(s.recv(true, false), Err(StreamError::AlreadyOpen()));
+        assert_eq!(s.recv(false, false), Err(StreamError::RecvAfterEnd()));
+        assert_eq!(s.send(true, false), Ok(Closed));
+        assert_eq!(Idle.recv(false, false), Err(StreamError::NotOpen()));
+        assert_eq!(Open.recv(true, false), Err(StreamError::AlreadyOpen()));
+    }
+}
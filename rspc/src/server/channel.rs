@@ -1,22 +1,35 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    rc::Rc,
+};
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::{tcp, TcpStream},
     sync::mpsc,
-    task,
+    task::{self, AbortHandle},
 };
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info};
 
 use crate::{
-    protocol::frame::{
-        FrameFlag, FrameHeader, ReplyFrame, RequestFlagBit, RequestFrame, RequestHeader,
-        REQUEST_FRAME_HEADER_LEN,
+    protocol::{
+        frame::{
+            is_control_request_id, FrameFlag, FrameHeader, ReplyFrame, RequestFlagBit,
+            RequestFrame, RequestHeader, REQUEST_FRAME_HEADER_LEN,
+        },
+        Metadata, Status, StreamState,
     },
     server::service::ServerReaderWriter,
 };
 
-use super::{error::ServerError, service::ServiceTable};
+use super::{
+    context::{CallContext, Extensions, PeerIdentity},
+    error::ServerError,
+    service::ServiceTable,
+};
 
 const CHANNEL_REPLY_BUF_SIZE: usize = 32;
 const CHANNEL_REQUEST_BUF_SIZE: usize = 32;
@@ -25,16 +38,130 @@ const CHANNEL_SERVICE_BUF_SIZE: usize = 8;
 pub struct Channel {
     stream: TcpStream,
     service_table: Rc<RefCell<ServiceTable>>,
+    peer_addr: Option<SocketAddr>,
+    peer_identity: Option<PeerIdentity>,
+    extensions: Extensions,
+    cancelled_calls: usize,
+}
+
+struct RunningCall {
+    request_id: u32,
+    // the reply half, a call which ended it no longer holds its request id
+    reply: Rc<Cell<StreamState>>,
+    abort: AbortHandle,
+    cancel: CancellationToken,
+}
+
+/// Service tasks spawned by one channel that have not finished yet.
+#[derive(Clone, Default)]
+struct RunningCalls {
+    tasks: Rc<RefCell<HashMap<u64, RunningCall>>>,
+    next_key: Rc<Cell<u64>>,
+    token: CancellationToken,
+}
+
+impl RunningCalls {
+    fn spawn<F>(
+        &self,
+        request_id: u32,
+        reply: Rc<Cell<StreamState>>,
+        f: impl FnOnce(CancellationToken) -> F,
+    ) where
+        F: futures::Future<Output = ()> + 'static,
+    {
+        let key = self.next_key.get();
+        self.next_key.set(key + 1);
+
+        let token = self.token.child_token();
+        let task = f(token.clone());
+        let tasks = self.tasks.clone();
+        let handle = task::spawn_local(async move {
+            task.await;
+            tasks.borrow_mut().remove(&key);
+        });
+        let call = RunningCall {
+            request_id,
+            reply,
+            abort: handle.abort_handle(),
+            cancel: token,
+        };
+        self.tasks.borrow_mut().insert(key, call);
+    }
+
+    /// A call of `request_id` may still reply.
+    fn is_replying(&self, request_id: u32) -> bool {
+        let tasks = self.tasks.borrow();
+        tasks
+            .values()
+            .any(|call| call.request_id == request_id && call.reply.get().can_send())
+    }
+
+    /// Signal and abort the latest call of `request_id`, false if it
+    /// finished.
+    fn cancel(&self, request_id: u32) -> bool {
+        let mut tasks = self.tasks.borrow_mut();
+        let key = tasks
+            .iter()
+            .filter(|(_, call)| call.request_id == request_id)
+            .map(|(key, _)| *key)
+            .max();
+        match key.and_then(|key| tasks.remove(&key)) {
+            Some(call) => {
+                call.cancel.cancel();
+                call.abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Signal and abort every unfinished call, return how many there were.
+    fn cancel_all(&self) -> usize {
+        self.token.cancel();
+        let tasks: Vec<_> = self.tasks.borrow_mut().drain().collect();
+        for (_, call) in tasks.iter() {
+            call.abort.abort();
+        }
+        tasks.len()
+    }
 }
 
 impl Channel {
     pub fn new(stream: TcpStream, service_table: Rc<RefCell<ServiceTable>>) -> Self {
         Channel {
+            peer_addr: stream.peer_addr().ok(),
             stream,
             service_table,
+            peer_identity: None,
+            extensions: Extensions::new(),
+            cancelled_calls: 0,
         }
     }
 
+    /// Calls that were still running when the connection closed.
+    pub fn cancelled_calls(&self) -> usize {
+        self.cancelled_calls
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Identity the peer authenticated with, visible to every call as
+    /// `CallContext::peer_identity`.
+    pub fn set_peer_identity(&mut self, peer_identity: PeerIdentity) {
+        self.peer_identity = Some(peer_identity);
+    }
+
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer_identity.as_ref()
+    }
+
+    /// Connection scoped state, visible to every call as `CallContext::extensions`.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
     pub async fn init(&mut self) {
         todo!()
     }
@@ -47,16 +174,32 @@ impl Channel {
         let (reply_tx, reply_rx) = mpsc::channel(CHANNEL_REPLY_BUF_SIZE);
         let (request_tx, request_rx) = mpsc::channel(CHANNEL_REQUEST_BUF_SIZE);
 
+        let calls = RunningCalls::default();
+
         let reader = Self::channel_reader(tcp_reader, request_tx);
-        let request_handler = Self::request_handler(request_rx, reply_tx, &self.service_table);
+        let request_handler = Self::request_handler(
+            request_rx,
+            reply_tx,
+            &self.service_table,
+            self.peer_addr,
+            &self.peer_identity,
+            &self.extensions,
+            &calls,
+        );
         let writer = Self::channel_writer(tcp_writer, reply_rx);
 
         let local = task::LocalSet::new();
-        let _ = local
+        let r = local
             .run_until(futures::future::try_join3(reader, request_handler, writer))
-            .await?;
+            .await;
 
-        Ok(())
+        let cancelled = calls.cancel_all();
+        if cancelled > 0 {
+            info!(cancelled, peer = ?self.peer_addr, "connection closed, cancel running calls");
+        }
+        self.cancelled_calls += cancelled;
+
+        r.map(|_| ())
     }
 
     async fn channel_reader(
@@ -78,16 +221,21 @@ impl Channel {
 
             request_tx.send(frame).await?;
         }
-        todo!()
     }
 
     async fn request_handler(
         mut request_rx: mpsc::Receiver<RequestFrame>,
         reply_tx: mpsc::Sender<ReplyFrame>,
         service_table: &Rc<RefCell<ServiceTable>>,
+        peer_addr: Option<SocketAddr>,
+        peer_identity: &Option<PeerIdentity>,
+        extensions: &Extensions,
+        calls: &RunningCalls,
     ) -> Result<(), ServerError> {
         // working service request stream record
         let working: RefCell<HashMap<u32, mpsc::Sender<RequestFrame>>> = RefCell::default();
+        // request ids of reset streams, their frames are dropped until EOS
+        let mut rejected: HashSet<u32> = HashSet::new();
 
         while let Some(frame) = request_rx.recv().await {
             let RequestFrame {
@@ -98,19 +246,74 @@ impl Channel {
                         ref flag,
                         body_len: _,
                     },
-                body: _,
+                ref body,
             } = frame;
 
             use RequestFlagBit::*;
-            // 3 flag: FIRST, EOS, SIGNAL
+            // the request half of a stream, as `StreamState::recv` moves it
+            //
+            // Idle             not recorded, a FIRST frame runs the service
+            // Open             recorded in `working`
+            // HalfClosedRemote the client sent EOS, the call may still reply
             //
-            // FIRST && !EOS    run service and record
-            // FIRST && EOS     run service not record
-            // !FIRST && EOS    remove from record
-            // !FIRST && !EOS   get from record
+            // METADATA         body is call metadata, only with FIRST
+            // CANCEL           stop the call, reply CANCELLED if it ran
             // !SIGNAL          send message
+            //
+            // a frame not allowed in its stream's state is dropped if a call
+            // runs on the stream, it resets an idle stream
+            if is_control_request_id(request_id) {
+                debug!(request_id, "control frame ignored");
+                continue;
+            }
+            if !flag.is(FIRST) && rejected.contains(&request_id) {
+                if flag.is(EOS) {
+                    rejected.remove(&request_id);
+                }
+                continue;
+            }
+            if flag.is(CANCEL) {
+                working.borrow_mut().remove(&request_id);
+                if flag.is(FIRST) || calls.cancel(request_id) {
+                    info!(request_id, "call cancelled");
+                    let status = Status::cancelled("cancelled by client");
+                    reply_tx
+                        .send(ReplyFrame::status(request_id, &status))
+                        .await?;
+                }
+                continue;
+            }
+
+            let state = if working.borrow().contains_key(&request_id) {
+                StreamState::Open
+            } else if calls.is_replying(request_id) {
+                StreamState::HalfClosedRemote
+            } else {
+                StreamState::Idle
+            };
+            let state = match state.recv(flag.is(FIRST), flag.is(EOS)) {
+                Ok(state) => state,
+                Err(e) => {
+                    // like an HTTP/2 stream error, also ends a call running on it
+                    error!(request_id, error = %e, "reset stream");
+                    working.borrow_mut().remove(&request_id);
+                    calls.cancel(request_id);
+                    Self::reject(&reply_tx, &mut rejected, &frame, &e.into()).await?;
+                    continue;
+                }
+            };
+
             let service_tx = if flag.is(FIRST) {
-                let service = service_table.borrow().get_service(method_id)?; // TODO: method_id Error handling
+                let service = service_table.borrow().get_service(method_id);
+                let service = match service {
+                    Ok(service) => service,
+                    Err(e) => {
+                        info!(request_id, method_id, error = %e, "reset stream");
+                        let status = Status::unimplemented(format!("no method {}", method_id));
+                        Self::reject(&reply_tx, &mut rejected, &frame, &status).await?;
+                        continue;
+                    }
+                };
 
                 info!(
                     service = service.service_name(),
@@ -118,18 +321,42 @@ impl Channel {
                     "call service method"
                 );
 
+                let metadata = if flag.is(METADATA) {
+                    match Metadata::decode(body.clone()) {
+                        Ok(metadata) => metadata,
+                        Err(e) => {
+                            info!(request_id, error = %e, "reset stream");
+                            let status = Status::invalid_argument("malformed call metadata");
+                            Self::reject(&reply_tx, &mut rejected, &frame, &status).await?;
+                            continue;
+                        }
+                    }
+                } else {
+                    Metadata::default()
+                };
+
+                let method = service.method_info(request_id);
                 let (service_tx, service_rx) = mpsc::channel(CHANNEL_SERVICE_BUF_SIZE);
                 let rw = ServerReaderWriter::new(reply_tx.clone(), service_rx, request_id);
-                task::spawn_local(async move {
-                    let service = service;
-                    let r = service.call(rw).await;
-                    if let Err(_) = r {
-                        // TODO: better error handling
-                        error!("call method error");
+                let extensions = extensions.clone();
+                let peer_identity = peer_identity.clone();
+                let reply = rw.reply_state();
+                let reply_tx = reply_tx.clone();
+                calls.spawn(request_id, rw.reply_state(), |cancel| async move {
+                    let ctx = CallContext::new(method, peer_addr, metadata, extensions, cancel)
+                        .with_peer_identity(peer_identity);
+                    if let Err(e) = service.call(ctx, rw).await {
+                        error!(request_id, error = %e, "call method error");
+                        // end the call for the client unless the method did
+                        if reply.get().can_send() {
+                            reply.set(reply.get().reset());
+                            let status = Status::internal(e.to_string());
+                            let _ = reply_tx.send(ReplyFrame::status(request_id, &status)).await;
+                        }
                     }
                 });
 
-                if !flag.is(EOS) {
+                if state == StreamState::Open {
                     let mut working = working.borrow_mut();
                     working.insert(request_id, service_tx.clone());
                 }
@@ -143,18 +370,41 @@ impl Channel {
                 let working = working.borrow_mut();
                 working
                     .get(&request_id)
-                    .map(|s| s.clone())
+                    .cloned()
                     .ok_or(ServerError::ServiceRecordError())?
             };
 
-            if !flag.is(SIGNAL) {
+            // an `EOS` is passed on even without a message, it tells the
+            // method's reader the client ended its side
+            if (!flag.is(SIGNAL) && !flag.is(METADATA)) || flag.is(EOS) {
                 // TODO: congestion handle, let one service method will not stuck whole server
-                service_tx.send(frame).await?;
+                // the call may have finished before the client ended its side
+                if service_tx.send(frame).await.is_err() {
+                    debug!(request_id, "request for finished call");
+                }
             }
         }
         todo!();
     }
 
+    /// Reset the stream of `frame` with `status`, its frames are dropped
+    /// until its `EOS`.
+    async fn reject(
+        reply_tx: &mpsc::Sender<ReplyFrame>,
+        rejected: &mut HashSet<u32>,
+        frame: &RequestFrame,
+        status: &Status,
+    ) -> Result<(), ServerError> {
+        let request_id = frame.header.request_id;
+        if !frame.header.flag.is(RequestFlagBit::EOS) {
+            rejected.insert(request_id);
+        }
+        reply_tx
+            .send(ReplyFrame::status(request_id, status))
+            .await?;
+        Ok(())
+    }
+
     async fn channel_writer(
         mut tcp_writer: BufWriter<tcp::WriteHalf<'_>>,
         mut reply_rx: mpsc::Receiver<ReplyFrame>,
@@ -171,3 +421,178 @@ impl Channel {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        protocol::{
+            frame::{ReplyFlagBit, ReplyHeader, RequestFlag, REPLY_FRAME_HEADER_LEN},
+            Code,
+        },
+        server::{Service, ServiceTable},
+    };
+
+    /// Waits until its call is cancelled, keeping the call's token.
+    #[derive(Clone, Default)]
+    struct Hang {
+        token: Rc<RefCell<Option<CancellationToken>>>,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl Service for Hang {
+        async fn call_method(
+            &self,
+            _fn_n: u32,
+            ctx: CallContext,
+            _stream: ServerReaderWriter,
+        ) -> Result<(), ServerError> {
+            *self.token.borrow_mut() = Some(ctx.cancellation_token().clone());
+            futures::future::pending().await
+        }
+
+        fn service_name(&self) -> &'static str {
+            "Hang"
+        }
+
+        fn methods_name(&self) -> &'static [&'static str] {
+            &["hang"]
+        }
+
+        fn methods_len(&self) -> usize {
+            1
+        }
+    }
+
+    /// Fails without replying.
+    struct Fail;
+
+    #[async_trait::async_trait(?Send)]
+    impl Service for Fail {
+        async fn call_method(
+            &self,
+            _fn_n: u32,
+            _ctx: CallContext,
+            _stream: ServerReaderWriter,
+        ) -> Result<(), ServerError> {
+            Err(ServerError::NormalRpcMethodError())
+        }
+
+        fn service_name(&self) -> &'static str {
+            "Fail"
+        }
+
+        fn methods_name(&self) -> &'static [&'static str] {
+            &["fail"]
+        }
+
+        fn methods_len(&self) -> usize {
+            1
+        }
+    }
+
+    /// A server channel serving `table` and the client end of it.
+    async fn connect(table: ServiceTable) -> (Channel, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        (Channel::new(stream, Rc::new(RefCell::new(table))), client)
+    }
+
+    async fn send(client: &mut TcpStream, request_id: u32, flag: RequestFlag, body: &[u8]) {
+        let header = RequestHeader {
+            request_id,
+            flag,
+            method_id: 0,
+            body_len: body.len() as u32,
+        };
+        client.write_all(&header.encode_to_array()).await.unwrap();
+        client.write_all(body).await.unwrap();
+    }
+
+    async fn recv(client: &mut TcpStream) -> ReplyFrame {
+        let mut header = [0u8; REPLY_FRAME_HEADER_LEN];
+        client.read_exact(&mut header).await.unwrap();
+        let header = ReplyHeader::decode(&header[..]).unwrap();
+        let mut body = vec![0u8; header.body_len as usize];
+        client.read_exact(&mut body).await.unwrap();
+        ReplyFrame {
+            header,
+            body: body.into(),
+        }
+    }
+
+    fn first() -> RequestFlag {
+        RequestFlag::default().set(RequestFlagBit::FIRST)
+    }
+
+    #[tokio::test]
+    async fn cancel_calls_on_close() {
+        let hang = Hang::default();
+        let mut table = ServiceTable::new();
+        table.register_service(hang.clone());
+        let (mut channel, mut client) = connect(table).await;
+
+        let call = async {
+            send(&mut client, 1, first(), b"request").await;
+            while hang.token.borrow().is_none() {
+                task::yield_now().await;
+            }
+            drop(client);
+        };
+        let (r, ()) = futures::join!(channel.run(), call);
+
+        assert!(r.is_err());
+        assert_eq!(channel.cancelled_calls(), 1);
+        assert!(hang.token.borrow().as_ref().unwrap().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn misplaced_frame_resets_stream() {
+        let hang = Hang::default();
+        let mut table = ServiceTable::new();
+        table.register_service(hang.clone());
+        let (mut channel, mut client) = connect(table).await;
+
+        let call = async {
+            send(&mut client, 1, first(), b"request").await;
+            while hang.token.borrow().is_none() {
+                task::yield_now().await;
+            }
+            // a second FIRST on the live stream
+            send(&mut client, 1, first(), b"again").await;
+            let reply = recv(&mut client).await;
+            assert_eq!(reply.header.request_id, 1);
+            assert_eq!(Code::from(reply.header.status_code), Code::AlreadyExists);
+            assert!(reply.header.flag.is(ReplyFlagBit::EOS));
+            assert!(hang.token.borrow().as_ref().unwrap().is_cancelled());
+        };
+        tokio::select! {
+            r = channel.run() => panic!("channel closed: {:?}", r.err()),
+            _ = call => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_call_gets_status() {
+        let mut table = ServiceTable::new();
+        table.register_service(Fail);
+        let (mut channel, mut client) = connect(table).await;
+
+        let call = async {
+            send(&mut client, 1, first().set(RequestFlagBit::EOS), b"request").await;
+            let reply = recv(&mut client).await;
+            assert_eq!(reply.header.request_id, 1);
+            assert_eq!(Code::from(reply.header.status_code), Code::Internal);
+            assert!(reply.header.flag.is(ReplyFlagBit::EOS));
+        };
+        tokio::select! {
+            r = channel.run() => panic!("channel closed: {:?}", r.err()),
+            _ = call => {}
+        }
+    }
+}
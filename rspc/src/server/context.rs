@@ -0,0 +1,246 @@
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::HashMap,
+    net::SocketAddr,
+    rc::Rc,
+    time::Instant,
+};
+
+use bytes::Bytes;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+use crate::protocol::{Metadata, Status};
+
+use super::interceptor::MethodInfo;
+
+/// Per call information built by the server channel and handed to
+/// `Service::call_method`.
+#[derive(Clone)]
+pub struct CallContext {
+    method: MethodInfo,
+    peer_addr: Option<SocketAddr>,
+    metadata: Metadata,
+    deadline: Option<Instant>,
+    peer_identity: Option<PeerIdentity>,
+    extensions: Extensions,
+    cancel: CancellationToken,
+}
+
+/// Certificates presented by the peer, set on the channel by whoever accepted
+/// the connection, e.g. after a TLS handshake.
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    pub certificates: Vec<Bytes>,
+}
+
+impl CallContext {
+    pub fn new(
+        method: MethodInfo,
+        peer_addr: Option<SocketAddr>,
+        metadata: Metadata,
+        extensions: Extensions,
+        cancel: CancellationToken,
+    ) -> Self {
+        let deadline = metadata.timeout().map(|t| Instant::now() + t);
+        Self {
+            method,
+            peer_addr,
+            metadata,
+            deadline,
+            peer_identity: None,
+            extensions,
+            cancel,
+        }
+    }
+
+    pub fn with_peer_identity(mut self, peer_identity: Option<PeerIdentity>) -> Self {
+        self.peer_identity = peer_identity;
+        self
+    }
+
+    pub fn method(&self) -> &MethodInfo {
+        &self.method
+    }
+
+    pub fn request_id(&self) -> u32 {
+        self.method.request_id
+    }
+
+    pub fn method_id(&self) -> u32 {
+        self.method.method_id
+    }
+
+    pub fn service_name(&self) -> &'static str {
+        self.method.service_name
+    }
+
+    pub fn method_name(&self) -> &'static str {
+        self.method.method_name
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+
+    /// Derived from the `rspc-timeout` metadata when the call arrived.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer_identity.as_ref()
+    }
+
+    /// Connection scoped state, shared by every call on the same channel.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Resolves when the call is cancelled, e.g. the client disconnected.
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.cancel.cancelled()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// Reject a call whose `content-type` metadata names another codec,
+    /// calls without one are assumed to match.
+    pub fn check_content_type(&self, expected: &str) -> Result<(), Status> {
+        match self.metadata.content_type() {
+            Some(content_type) if content_type != expected => {
+                Err(Status::invalid_argument(format!(
+                    "unsupported content-type {}, expect {}",
+                    content_type, expected
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A type map shared by all calls of one connection.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: Rc<RefCell<HashMap<TypeId, Box<dyn Any>>>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<T: 'static>(&self, value: T) -> Option<T> {
+        self.map
+            .borrow_mut()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: Clone + 'static>(&self) -> Option<T> {
+        self.map
+            .borrow()
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref::<T>())
+            .cloned()
+    }
+
+    pub fn remove<T: 'static>(&self) -> Option<T> {
+        self.map
+            .borrow_mut()
+            .remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    /// Run `f` on the stored value, inserting `T::default()` first if missing.
+    ///
+    /// The value is taken out of the map while `f` runs, so `f` may use the
+    /// extensions freely but a nested access to the same `T` sees it missing.
+    pub fn with<T: Default + 'static, R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let key = TypeId::of::<T>();
+        let entry = self.map.borrow_mut().remove(&key);
+        // keyed by its type, the value is always a `T`
+        let mut value = entry.map_or_else(Box::default, |v| v.downcast::<T>().unwrap());
+        let r = f(&mut value);
+        self.map.borrow_mut().insert(key, value);
+        r
+    }
+}
+
+/// Extractor style handler argument, see `ctx` in `rspc_server`.
+pub trait FromCallContext: Sized {
+    fn from_call_context(ctx: &CallContext) -> Result<Self, Status>;
+}
+
+impl FromCallContext for CallContext {
+    fn from_call_context(ctx: &CallContext) -> Result<Self, Status> {
+        Ok(ctx.clone())
+    }
+}
+
+impl FromCallContext for MethodInfo {
+    fn from_call_context(ctx: &CallContext) -> Result<Self, Status> {
+        Ok(ctx.method.clone())
+    }
+}
+
+impl FromCallContext for Metadata {
+    fn from_call_context(ctx: &CallContext) -> Result<Self, Status> {
+        Ok(ctx.metadata.clone())
+    }
+}
+
+impl FromCallContext for Extensions {
+    fn from_call_context(ctx: &CallContext) -> Result<Self, Status> {
+        Ok(ctx.extensions.clone())
+    }
+}
+
+impl FromCallContext for PeerIdentity {
+    fn from_call_context(ctx: &CallContext) -> Result<Self, Status> {
+        ctx.peer_identity
+            .clone()
+            .ok_or_else(|| Status::unauthenticated("peer identity unknown"))
+    }
+}
+
+impl FromCallContext for SocketAddr {
+    fn from_call_context(ctx: &CallContext) -> Result<Self, Status> {
+        ctx.peer_addr
+            .ok_or_else(|| Status::internal("peer address unknown"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_reentrant() {
+        let extensions = Extensions::new();
+        extensions.insert(String::from("conn"));
+        let n = extensions.with(|calls: &mut u32| {
+            *calls += 1;
+            // other types stay reachable while `calls` is borrowed
+            assert_eq!(extensions.get::<String>().as_deref(), Some("conn"));
+            assert_eq!(extensions.get::<u32>(), None);
+            *calls
+        });
+        assert_eq!(n, 1);
+        assert_eq!(extensions.get::<u32>(), Some(1));
+    }
+}
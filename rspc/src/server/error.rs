@@ -1,6 +1,9 @@
 use tokio::sync::mpsc;
 
-use crate::protocol::frame::{FrameError, ReplyFrame, RequestFrame};
+use crate::protocol::{
+    frame::{FrameError, ReplyFrame, RequestFrame},
+    StreamError,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ServerError {
@@ -16,6 +19,9 @@ pub enum ServerError {
     #[error("framing error")]
     FrameError(#[from] FrameError),
 
+    #[error("decode message error")]
+    DecodeError(#[from] prost::DecodeError),
+
     #[error("not FIRST request but can't search in record table")]
     ServiceRecordError(),
 
@@ -27,4 +33,10 @@ pub enum ServerError {
 
     #[error("stream rpc method run error")]
     StreamRpcMethodError(),
+
+    #[error("stream error: {0}")]
+    Stream(#[from] StreamError),
+
+    #[error("reply channel closed")]
+    ReplyChannelClosed(),
 }
@@ -0,0 +1,166 @@
+use std::rc::Rc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::protocol::Status;
+
+use super::context::CallContext;
+
+/// Identity of the method a call is routed to.
+#[derive(Debug, Clone)]
+pub struct MethodInfo {
+    pub service_name: &'static str,
+    pub method_name: &'static str,
+    pub method_id: u32,
+    pub request_id: u32,
+}
+
+/// Hooks run around `ServiceMethod::call`.
+///
+/// `on_call` runs in registration order before the method, the first `Err`
+/// rejects the call and the status is sent to the client instead.
+/// `on_request` and `on_reply` see every message of the call in the same
+/// order and may rewrite its body. An `Err` from `on_request` resets the
+/// request stream, the method reads it as `ReadEvent::Reset`.
+#[async_trait(?Send)]
+pub trait Interceptor {
+    async fn on_call(&self, _ctx: &mut CallContext) -> Result<(), Status> {
+        Ok(())
+    }
+
+    fn on_request(&self, _method: &MethodInfo, _body: &mut Bytes) -> Result<(), Status> {
+        Ok(())
+    }
+
+    fn on_reply(&self, _method: &MethodInfo, _status_code: u32, _body: &mut Bytes) {}
+}
+
+#[derive(Clone, Default)]
+pub struct InterceptorChain {
+    interceptors: Vec<Rc<dyn Interceptor>>,
+}
+
+impl InterceptorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<I: 'static + Interceptor>(&mut self, interceptor: I) {
+        self.interceptors.push(Rc::new(interceptor));
+    }
+
+    pub fn with<I: 'static + Interceptor>(mut self, interceptor: I) -> Self {
+        self.push(interceptor);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// `self` runs first, then `inner`.
+    pub(crate) fn chain(&self, inner: &InterceptorChain) -> InterceptorChain {
+        let mut interceptors = self.interceptors.clone();
+        interceptors.extend(inner.interceptors.iter().cloned());
+        InterceptorChain { interceptors }
+    }
+
+    pub(crate) async fn on_call(&self, ctx: &mut CallContext) -> Result<(), Status> {
+        for i in self.interceptors.iter() {
+            i.on_call(ctx).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn on_request(&self, method: &MethodInfo, body: &mut Bytes) -> Result<(), Status> {
+        for i in self.interceptors.iter() {
+            i.on_request(method, body)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn on_reply(&self, method: &MethodInfo, status_code: u32, body: &mut Bytes) {
+        for i in self.interceptors.iter() {
+            i.on_reply(method, status_code, body);
+        }
+    }
+}
+
+/// Frame observer installed into a call's reader and writer.
+#[derive(Clone)]
+pub(crate) struct CallObserver {
+    pub(crate) method: Rc<MethodInfo>,
+    pub(crate) chain: InterceptorChain,
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        protocol::{frame::*, Code, ReadEvent},
+        server::ServerReaderWriter,
+    };
+
+    struct Rewrite;
+
+    impl Interceptor for Rewrite {
+        fn on_request(&self, _method: &MethodInfo, body: &mut Bytes) -> Result<(), Status> {
+            if body.as_ref() == b"bad" {
+                return Err(Status::invalid_argument("bad request"));
+            }
+            *body = body.to_ascii_uppercase().into();
+            Ok(())
+        }
+
+        fn on_reply(&self, _method: &MethodInfo, _status_code: u32, body: &mut Bytes) {
+            *body = [body.as_ref(), b"!"].concat().into();
+        }
+    }
+
+    fn request(body: &'static [u8]) -> RequestFrame {
+        RequestFrame {
+            header: RequestHeader {
+                request_id: 7,
+                flag: RequestFlag::default(),
+                method_id: 0,
+                body_len: body.len() as u32,
+            },
+            body: Bytes::from_static(body),
+        }
+    }
+
+    #[tokio::test]
+    async fn rewrite_frames() {
+        let (reply_tx, mut reply_rx) = mpsc::channel(8);
+        let (request_tx, request_rx) = mpsc::channel(8);
+        let mut rw = ServerReaderWriter::new(reply_tx, request_rx, 7);
+        rw.observe(CallObserver {
+            method: Rc::new(MethodInfo {
+                service_name: "Hello",
+                method_name: "hello",
+                method_id: 0,
+                request_id: 7,
+            }),
+            chain: InterceptorChain::new().with(Rewrite),
+        });
+
+        request_tx.send(request(b"hi")).await.unwrap();
+        request_tx.send(request(b"bad")).await.unwrap();
+        assert_eq!(
+            rw.read_event().await,
+            ReadEvent::Message(Bytes::from_static(b"HI"))
+        );
+        match rw.read_event().await {
+            ReadEvent::Reset(status) => assert_eq!(status.code(), Code::InvalidArgument),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        rw.write(0, Bytes::from_static(b"ok")).await.unwrap();
+        let reply = reply_rx.recv().await.unwrap();
+        assert_eq!(reply.body, Bytes::from_static(b"ok!"));
+        assert_eq!(reply.header.body_len, 3);
+    }
+}
@@ -6,13 +6,22 @@ use tracing::info;
 use self::service::ServiceTable;
 
 pub mod channel;
+pub mod context;
 pub mod error;
+pub mod interceptor;
 pub mod service;
+pub mod streaming;
+pub mod tower_adapter;
 
 pub use channel::Channel;
+pub use context::{CallContext, Extensions, FromCallContext, PeerIdentity};
 pub use error::ServerError;
+pub use interceptor::{Interceptor, InterceptorChain, MethodInfo};
 pub use service::ServerReaderWriter;
 pub use service::Service;
+pub use service::{ServerReader, ServerWriter};
+pub use streaming::{ReplySink, RequestStream};
+pub use tower_adapter::{TowerRequest, TowerService};
 
 pub struct Server {
     listener: TcpListener,
@@ -32,6 +41,24 @@ impl Server {
         self.service_table.borrow_mut().register_service(service);
     }
 
+    pub fn register_service_with_interceptors<S: 'static + Service>(
+        &mut self,
+        service: S,
+        interceptors: InterceptorChain,
+    ) {
+        self.service_table
+            .borrow_mut()
+            .register_service_with_interceptors(service, interceptors);
+    }
+
+    /// Add an interceptor run for every service, before per service ones.
+    pub fn add_interceptor<I: 'static + Interceptor>(&mut self, interceptor: I) {
+        self.service_table
+            .borrow_mut()
+            .interceptors_mut()
+            .push(interceptor);
+    }
+
     pub fn list_service(&self) -> Vec<(&'static str, &'static str)> {
         self.service_table.borrow().list_service()
     }
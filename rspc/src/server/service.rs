@@ -1,16 +1,37 @@
-use std::{collections::HashMap, rc::Rc};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use futures::{future::poll_fn, ready, Sink, Stream};
 use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
 
-use crate::protocol::frame::*;
+use crate::{
+    codec::Codec,
+    protocol::{frame::*, ReadEvent, Status, StreamState},
+};
 
-use super::error::ServerError;
+use super::{
+    context::CallContext,
+    error::ServerError,
+    interceptor::{CallObserver, InterceptorChain, MethodInfo},
+    streaming::{ReplySink, RequestStream},
+};
 
 #[async_trait(?Send)]
 pub trait Service {
-    async fn call_method(&self, fn_n: u32, stream: ServerReaderWriter) -> Result<(), ServerError>;
+    async fn call_method(
+        &self,
+        fn_n: u32,
+        ctx: CallContext,
+        stream: ServerReaderWriter,
+    ) -> Result<(), ServerError>;
 
     fn service_name(&self) -> &'static str;
 
@@ -36,6 +57,14 @@ impl ServerReaderWriter {
         }
     }
 
+    pub fn request_id(&self) -> u32 {
+        self.writer.request_id
+    }
+
+    pub(crate) fn reply_state(&self) -> Rc<Cell<StreamState>> {
+        self.writer.state.clone()
+    }
+
     pub async fn write(&self, status_code: u32, reply_body: Bytes) -> Result<(), ServerError> {
         self.writer.write(status_code, reply_body).await
     }
@@ -44,72 +73,152 @@ impl ServerReaderWriter {
         self.writer.write_last(status_code, reply_body).await
     }
 
+    /// End the reply stream, requests can still be read.
     pub async fn write_complete(&self) -> Result<(), ServerError> {
         self.writer.write_complete().await
     }
 
+    pub async fn write_status(&self, status: &Status) -> Result<(), ServerError> {
+        self.writer.write_status(status).await
+    }
+
     pub async fn read(&mut self) -> Option<Bytes> {
         self.reader.read().await
     }
 
+    pub async fn read_event(&mut self) -> ReadEvent<Bytes> {
+        self.reader.read_event().await
+    }
+
+    /// Read the only request of a unary call, the client must end its side
+    /// after exactly one message.
+    pub async fn read_unary(&mut self) -> Result<Bytes, Status> {
+        let request = match self.read_event().await {
+            ReadEvent::Message(request) => request,
+            ReadEvent::EndOfStream => {
+                return Err(Status::invalid_argument("unary call without request"))
+            }
+            ReadEvent::Reset(status) => return Err(status),
+        };
+        match self.read_event().await {
+            ReadEvent::Message(_) => Err(Status::invalid_argument(
+                "unary call with more than one request",
+            )),
+            ReadEvent::EndOfStream => Ok(request),
+            ReadEvent::Reset(status) => Err(status),
+        }
+    }
+
+    pub(crate) fn observe(&mut self, observer: CallObserver) {
+        self.reader.observer = Some(observer.clone());
+        self.writer.observer = Some(observer);
+    }
+
     pub fn split(self) -> (ServerReader, ServerWriter) {
         (self.reader, self.writer)
     }
+
+    /// Decode requests as `Req` and encode replies from `Reply` with `C`.
+    pub fn typed<Req, Reply, C>(self) -> (RequestStream<Req, C>, ReplySink<Reply, C>)
+    where
+        C: Codec<Req> + Codec<Reply>,
+    {
+        (RequestStream::new(self.reader), ReplySink::new(self.writer))
+    }
 }
 
+/// Also a `Sink` of status 0 messages, closing it ends the reply stream.
 #[derive(Clone)]
 pub struct ServerWriter {
     writer_chan: mpsc::Sender<ReplyFrame>,
+    sink: PollSender<ReplyFrame>,
     request_id: u32,
+    observer: Option<CallObserver>,
+    // the reply half, shared by the clones of one call
+    state: Rc<Cell<StreamState>>,
 }
 
 impl ServerWriter {
     fn new(writer_chan: mpsc::Sender<ReplyFrame>, request_id: u32) -> Self {
         Self {
+            sink: PollSender::new(writer_chan.clone()),
             writer_chan,
             request_id,
+            observer: None,
+            // the client's FIRST frame opened the stream
+            state: Rc::new(Cell::new(StreamState::Open)),
         }
     }
 
     pub async fn write(&self, status_code: u32, reply_body: Bytes) -> Result<(), ServerError> {
-        self.write_msg(ReplyFrame {
-            header: ReplyHeader {
-                request_id: self.request_id,
-                flag: ReplyFlag::default(),
-                status_code,
-                body_len: reply_body.len() as u32,
-            },
-            body: reply_body,
-        })
-        .await
+        let msg = self.frame(ReplyFlag::default(), status_code, reply_body)?;
+        self.write_msg(msg).await
     }
 
     pub async fn write_last(&self, status_code: u32, reply_body: Bytes) -> Result<(), ServerError> {
         use ReplyFlagBit::*;
-        self.write_msg(ReplyFrame {
-            header: ReplyHeader {
-                request_id: self.request_id,
-                flag: ReplyFlag::default().set(EOS),
-                status_code,
-                body_len: reply_body.len() as u32,
-            },
-            body: reply_body,
-        })
-        .await
+        let msg = self.frame(ReplyFlag::default().set(EOS), status_code, reply_body)?;
+        self.write_msg(msg).await
     }
 
     pub async fn write_complete(&self) -> Result<(), ServerError> {
+        let msg = self.complete_frame()?;
+        self.write_msg(msg).await
+    }
+
+    pub async fn write_status(&self, status: &Status) -> Result<(), ServerError> {
+        use ReplyFlagBit::*;
+        let body = Bytes::copy_from_slice(status.message().as_bytes());
+        let msg = self.frame(
+            ReplyFlag::default().set(EOS).set(SIGNAL),
+            status.code().into(),
+            body,
+        )?;
+        self.write_msg(msg).await
+    }
+
+    /// End the reply stream with the handler result, unless the handler
+    /// already ended it.
+    pub async fn finish(&self, result: Result<(), Status>) -> Result<(), ServerError> {
+        match result {
+            _ if self.is_ended() => Ok(()),
+            Ok(()) => self.write_complete().await,
+            Err(status) => self.write_status(&status).await,
+        }
+    }
+
+    pub fn is_ended(&self) -> bool {
+        !self.state.get().can_send()
+    }
+
+    fn complete_frame(&self) -> Result<ReplyFrame, ServerError> {
         use ReplyFlagBit::*;
-        self.write_msg(ReplyFrame {
+        self.frame(ReplyFlag::default().set(EOS).set(SIGNAL), 0, Bytes::new())
+    }
+
+    /// Build the next reply frame of this call, interceptors see it here.
+    fn frame(
+        &self,
+        flag: ReplyFlag,
+        status_code: u32,
+        mut body: Bytes,
+    ) -> Result<ReplyFrame, ServerError> {
+        use ReplyFlagBit::*;
+        let empty_end = flag.is(EOS) && flag.is(SIGNAL);
+        let state = self.state.get().send(flag.is(EOS), empty_end)?;
+        self.state.set(state);
+        if let Some(CallObserver { method, chain }) = &self.observer {
+            chain.on_reply(method, status_code, &mut body);
+        }
+        Ok(ReplyFrame {
             header: ReplyHeader {
                 request_id: self.request_id,
-                flag: ReplyFlag::default().set(EOS).set(SIGNAL),
-                status_code: 0,
-                body_len: 0,
+                flag,
+                status_code,
+                body_len: body.len() as u32,
             },
-            body: Bytes::new(),
+            body,
         })
-        .await
     }
 
     async fn write_msg(&self, msg: ReplyFrame) -> Result<(), ServerError> {
@@ -117,28 +226,137 @@ impl ServerWriter {
     }
 }
 
+impl Sink<Bytes> for ServerWriter {
+    type Error = ServerError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ServerError>> {
+        self.get_mut()
+            .sink
+            .poll_reserve(cx)
+            .map_err(|_| ServerError::ReplyChannelClosed())
+    }
+
+    fn start_send(self: Pin<&mut Self>, reply_body: Bytes) -> Result<(), ServerError> {
+        let this = self.get_mut();
+        let msg = this.frame(ReplyFlag::default(), 0, reply_body)?;
+        this.sink
+            .send_item(msg)
+            .map_err(|_| ServerError::ReplyChannelClosed())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ServerError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ServerError>> {
+        let this = self.get_mut();
+        if this.is_ended() {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(this.sink.poll_reserve(cx)).map_err(|_| ServerError::ReplyChannelClosed())?;
+        let msg = this.complete_frame()?;
+        this.sink
+            .send_item(msg)
+            .map_err(|_| ServerError::ReplyChannelClosed())?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Also a `Stream` of request messages, it ends with the request stream.
+///
+/// The request half ends on its own, the reply half can still be written
+/// after it and the other way round.
 pub struct ServerReader {
     reader_chan: mpsc::Receiver<RequestFrame>,
+    observer: Option<CallObserver>,
+    // the client sent EOS
+    ended: bool,
+    // an interceptor refused a request
+    reset: Option<Status>,
 }
 
 impl ServerReader {
     fn new(reader_chan: mpsc::Receiver<RequestFrame>) -> Self {
-        Self { reader_chan }
+        Self {
+            reader_chan,
+            observer: None,
+            ended: false,
+            reset: None,
+        }
     }
 
+    /// `None` once the request stream ended or was reset, `read_event`
+    /// tells the two apart.
     pub async fn read(&mut self) -> Option<Bytes> {
-        let frame = self.reader_chan.recv().await;
-        frame.map(|frame| frame.body)
+        poll_fn(|cx| self.poll_read(cx)).await
+    }
+
+    pub async fn read_event(&mut self) -> ReadEvent<Bytes> {
+        poll_fn(|cx| self.poll_read_event(cx)).await
+    }
+
+    pub fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.poll_read_event(cx).map(ReadEvent::into_message)
+    }
+
+    /// `EndOfStream` after the client's `EOS`, `Reset` if the request
+    /// stream closed without one, as when the connection is lost, or an
+    /// interceptor refused a request.
+    pub fn poll_read_event(&mut self, cx: &mut Context<'_>) -> Poll<ReadEvent<Bytes>> {
+        use RequestFlagBit::*;
+        loop {
+            if let Some(status) = &self.reset {
+                return Poll::Ready(ReadEvent::Reset(status.clone()));
+            }
+            if self.ended {
+                return Poll::Ready(ReadEvent::EndOfStream);
+            }
+            let frame = match ready!(self.reader_chan.poll_recv(cx)) {
+                Some(frame) => frame,
+                None => {
+                    let status = Status::cancelled("request stream closed before its end");
+                    return Poll::Ready(ReadEvent::Reset(status));
+                }
+            };
+            let flag = frame.header.flag;
+            self.ended = flag.is(EOS);
+            // an empty `EOS | SIGNAL` frame only ends the stream
+            if flag.is(SIGNAL) || flag.is(METADATA) {
+                continue;
+            }
+            let mut body = frame.body;
+            if let Some(CallObserver { method, chain }) = &self.observer {
+                if let Err(status) = chain.on_request(method, &mut body) {
+                    self.reset = Some(status);
+                    continue;
+                }
+            }
+            return Poll::Ready(ReadEvent::Message(body));
+        }
+    }
+}
+
+impl Stream for ServerReader {
+    type Item = Bytes;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.get_mut().poll_read(cx)
     }
 }
 
 #[derive(Default)]
 pub struct ServiceTable {
     id_map: HashMap<u32, ServiceMethod>,
+    interceptors: InterceptorChain,
 }
 
 #[derive(Clone)]
-pub struct ServiceMethod(Rc<dyn Service>, u32);
+pub struct ServiceMethod {
+    service: Rc<dyn Service>,
+    fn_n: u32,
+    method_id: u32,
+    interceptors: InterceptorChain,
+}
 
 impl ServiceTable {
     pub fn new() -> Self {
@@ -146,41 +364,91 @@ impl ServiceTable {
     }
 
     pub fn register_service<S: 'static + Service>(&mut self, service: S) {
+        self.register_service_with_interceptors(service, InterceptorChain::new());
+    }
+
+    pub fn register_service_with_interceptors<S: 'static + Service>(
+        &mut self,
+        service: S,
+        interceptors: InterceptorChain,
+    ) {
         let service: Rc<dyn Service> = Rc::new(service);
         let map_len = self.id_map.len();
         for i in 0..service.methods_len() {
+            let method_id = (map_len + i) as u32;
             self.id_map.insert(
-                (map_len + i) as u32,
-                ServiceMethod(service.clone(), i as u32),
+                method_id,
+                ServiceMethod {
+                    service: service.clone(),
+                    fn_n: i as u32,
+                    method_id,
+                    interceptors: interceptors.clone(),
+                },
             );
         }
     }
 
+    /// Interceptors added here run for every service, before the
+    /// per service ones.
+    pub fn interceptors_mut(&mut self) -> &mut InterceptorChain {
+        &mut self.interceptors
+    }
+
     pub fn get_service(&self, method_id: u32) -> Result<ServiceMethod, ServerError> {
-        self.id_map
+        let mut method = self
+            .id_map
             .get(&method_id)
-            .map(|m| m.clone())
-            .ok_or(ServerError::ErrorServiceMethodId())
+            .cloned()
+            .ok_or(ServerError::ErrorServiceMethodId())?;
+        if !self.interceptors.is_empty() {
+            method.interceptors = self.interceptors.chain(&method.interceptors);
+        }
+        Ok(method)
     }
 
     pub fn list_service(&self) -> Vec<(&'static str, &'static str)> {
         self.id_map
-            .iter()
-            .map(|(_, m)| (m.service_name(), m.method_name()))
+            .values()
+            .map(|m| (m.service_name(), m.method_name()))
             .collect()
     }
 }
 
 impl ServiceMethod {
-    pub async fn call(&self, stream: ServerReaderWriter) -> Result<(), ServerError> {
-        self.0.call_method(self.1, stream).await
+    pub async fn call(
+        &self,
+        mut ctx: CallContext,
+        mut stream: ServerReaderWriter,
+    ) -> Result<(), ServerError> {
+        if self.interceptors.is_empty() {
+            return self.service.call_method(self.fn_n, ctx, stream).await;
+        }
+
+        if let Err(status) = self.interceptors.on_call(&mut ctx).await {
+            return stream.write_status(&status).await;
+        }
+
+        stream.observe(CallObserver {
+            method: Rc::new(ctx.method().clone()),
+            chain: self.interceptors.clone(),
+        });
+        self.service.call_method(self.fn_n, ctx, stream).await
+    }
+
+    pub fn method_info(&self, request_id: u32) -> MethodInfo {
+        MethodInfo {
+            service_name: self.service_name(),
+            method_name: self.method_name(),
+            method_id: self.method_id,
+            request_id,
+        }
     }
 
     pub fn method_name(&self) -> &'static str {
-        self.0.methods_name()[self.1 as usize]
+        self.service.methods_name()[self.fn_n as usize]
     }
 
     pub fn service_name(&self) -> &'static str {
-        self.0.service_name()
+        self.service.service_name()
     }
 }
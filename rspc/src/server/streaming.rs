@@ -0,0 +1,201 @@
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Sink, SinkExt, Stream};
+
+use crate::{
+    codec::{Codec, ProstCodec},
+    protocol::{ReadEvent, Status},
+};
+
+use super::{
+    error::ServerError,
+    service::{ServerReader, ServerWriter},
+};
+
+/// Decoded requests of a `client_stream` or `bidi` call.
+pub struct RequestStream<T, C = ProstCodec> {
+    reader: ServerReader,
+    done: bool,
+    _codec: PhantomData<fn() -> (T, C)>,
+}
+
+impl<T, C: Codec<T>> RequestStream<T, C> {
+    pub fn new(reader: ServerReader) -> Self {
+        Self {
+            reader,
+            done: false,
+            _codec: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> ServerReader {
+        self.reader
+    }
+}
+
+impl<T, C: Codec<T>> Stream for RequestStream<T, C> {
+    type Item = Result<T, Status>;
+
+    /// A reset request stream ends with its status.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        match ready!(this.reader.poll_read_event(cx)) {
+            ReadEvent::Message(body) => Poll::Ready(Some(C::decode(body))),
+            ReadEvent::EndOfStream => {
+                this.done = true;
+                Poll::Ready(None)
+            }
+            ReadEvent::Reset(status) => {
+                this.done = true;
+                Poll::Ready(Some(Err(status)))
+            }
+        }
+    }
+}
+
+/// Encoding reply sink of a `server_stream` or `bidi` call.
+///
+/// Closing the sink ends the reply stream, otherwise it is ended when the
+/// handler returns.
+pub struct ReplySink<T, C = ProstCodec> {
+    writer: ServerWriter,
+    _codec: PhantomData<fn(T) -> C>,
+}
+
+impl<T, C: Codec<T>> ReplySink<T, C> {
+    pub fn new(writer: ServerWriter) -> Self {
+        Self {
+            writer,
+            _codec: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> ServerWriter {
+        self.writer
+    }
+}
+
+fn to_status(e: ServerError) -> Status {
+    Status::unavailable(e.to_string())
+}
+
+impl<T, C: Codec<T>> Sink<T> for ReplySink<T, C> {
+    type Error = Status;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        self.get_mut()
+            .writer
+            .poll_ready_unpin(cx)
+            .map_err(to_status)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Status> {
+        let body = C::encode(&item)?;
+        self.get_mut()
+            .writer
+            .start_send_unpin(body)
+            .map_err(to_status)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        self.get_mut()
+            .writer
+            .poll_flush_unpin(cx)
+            .map_err(to_status)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        self.get_mut()
+            .writer
+            .poll_close_unpin(cx)
+            .map_err(to_status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{stream, SinkExt, StreamExt};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        codec::JsonCodec,
+        protocol::{
+            frame::{
+                FrameFlag, ReplyFlagBit, RequestFlag, RequestFlagBit, RequestFrame, RequestHeader,
+            },
+            Code, ReadEvent,
+        },
+        server::ServerReaderWriter,
+    };
+
+    #[tokio::test]
+    async fn typed_stream_and_sink() {
+        let (reply_tx, mut reply_rx) = mpsc::channel(8);
+        let (request_tx, request_rx) = mpsc::channel(8);
+        let rw = ServerReaderWriter::new(reply_tx, request_rx, 7);
+        let (requests, mut replies) = rw.typed::<u32, String, JsonCodec>();
+
+        for body in ["1", "2"] {
+            let body = Bytes::from_static(body.as_bytes());
+            let mut flag = RequestFlag::default();
+            if body == "2" {
+                flag.set_in_place(RequestFlagBit::EOS);
+            }
+            let header = RequestHeader {
+                request_id: 7,
+                flag,
+                method_id: 0,
+                body_len: body.len() as u32,
+            };
+            request_tx
+                .send(RequestFrame { header, body })
+                .await
+                .unwrap();
+        }
+        drop(request_tx);
+
+        let requests: Vec<_> = requests.map(|r| r.unwrap()).collect().await;
+        assert_eq!(requests, vec![1, 2]);
+
+        let mut items = stream::iter(["a", "b"]).map(|s| Ok(s.to_string()));
+        replies.send_all(&mut items).await.unwrap();
+        replies.close().await.unwrap();
+        assert!(replies.send("c".to_string()).await.is_err());
+
+        let mut frames = vec![];
+        while let Ok(frame) = reply_rx.try_recv() {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].body, Bytes::from_static(b"\"a\""));
+        assert!(!frames[1].header.flag.is(ReplyFlagBit::EOS));
+        assert!(frames[2].header.flag.is(ReplyFlagBit::EOS));
+        assert!(frames[2].header.flag.is(ReplyFlagBit::SIGNAL));
+    }
+
+    #[tokio::test]
+    async fn request_stream_reset() {
+        let (reply_tx, _reply_rx) = mpsc::channel(8);
+        let (request_tx, request_rx) = mpsc::channel(8);
+        let mut rw = ServerReaderWriter::new(reply_tx, request_rx, 7);
+        drop(request_tx);
+
+        // closed without EOS, as when the connection is lost
+        match rw.read_event().await {
+            ReadEvent::Reset(status) => assert_eq!(status.code(), Code::Cancelled),
+            e => panic!("unexpected {:?}", e),
+        }
+        let (requests, _) = rw.typed::<u32, String, JsonCodec>();
+        let requests: Vec<_> = requests.collect().await;
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].is_err());
+    }
+}
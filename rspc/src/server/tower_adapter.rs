@@ -0,0 +1,204 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use tower::buffer::Buffer;
+use tower::{BoxError, ServiceExt};
+
+use crate::protocol::{Metadata, Status};
+
+use super::{CallContext, ServerError, ServerReaderWriter, Service};
+
+/// Request handed to a tower service registered through `TowerService`.
+#[derive(Debug)]
+pub struct TowerRequest {
+    /// index into the `methods_name` given to `TowerService::new`
+    pub fn_n: u32,
+    pub metadata: Metadata,
+    pub body: Bytes,
+}
+
+/// Number of calls that may wait for the tower service to become ready.
+const BUFFER_SIZE: usize = 1024;
+
+/// Route the unary methods of an rspc service into a `tower::Service`.
+///
+/// All calls share one instance of the tower service behind a
+/// `tower::buffer::Buffer`, so readiness from layers like `concurrency_limit`
+/// or `load_shed` holds across calls.
+///
+/// Errors returned by the tower service are sent to the client as a status,
+/// a `Status` error keeps its code, any other error becomes `Internal`.
+pub struct TowerService<S>
+where
+    S: tower::Service<TowerRequest>,
+{
+    inner: Buffer<S, TowerRequest>,
+    service_name: &'static str,
+    methods_name: &'static [&'static str],
+}
+
+impl<S> TowerService<S>
+where
+    S: tower::Service<TowerRequest> + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError> + Send + Sync,
+{
+    /// Spawns the buffer worker, so it must be called inside a tokio runtime.
+    pub fn new(
+        service_name: &'static str,
+        methods_name: &'static [&'static str],
+        inner: S,
+    ) -> Self {
+        Self {
+            inner: Buffer::new(inner, BUFFER_SIZE),
+            service_name,
+            methods_name,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<S> Service for TowerService<S>
+where
+    S: tower::Service<TowerRequest, Response = (u32, Bytes)> + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError> + Send + Sync,
+{
+    async fn call_method(
+        &self,
+        fn_n: u32,
+        ctx: CallContext,
+        mut stream: ServerReaderWriter,
+    ) -> Result<(), ServerError> {
+        let body = match stream.read_unary().await {
+            Ok(body) => body,
+            Err(status) => return stream.write_status(&status).await,
+        };
+        let request = TowerRequest {
+            fn_n,
+            metadata: ctx.metadata().clone(),
+            body,
+        };
+
+        // the clone is a handle on the shared worker, not a new service
+        let mut inner = self.inner.clone();
+        let reply = match inner.ready().await {
+            Ok(inner) => tower::Service::call(inner, request).await,
+            Err(e) => Err(e),
+        };
+        match reply {
+            Ok((status_code, body)) => stream.write_last(status_code, body).await,
+            Err(e) => {
+                let status = match e.downcast::<Status>() {
+                    Ok(status) => *status,
+                    Err(e) => Status::internal(e.to_string()),
+                };
+                stream.write_status(&status).await
+            }
+        }
+    }
+
+    fn service_name(&self) -> &'static str {
+        self.service_name
+    }
+
+    fn methods_name(&self) -> &'static [&'static str] {
+        self.methods_name
+    }
+
+    fn methods_len(&self) -> usize {
+        self.methods_name.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{sync::mpsc, time};
+    use tokio_util::sync::CancellationToken;
+    use tower::{service_fn, ServiceBuilder};
+
+    use super::*;
+    use crate::{
+        protocol::{frame::*, ReplyFrame, RequestFrame},
+        server::{Extensions, MethodInfo},
+    };
+
+    async fn call(service: &impl Service, body: &'static [u8]) -> ReplyFrame {
+        let (reply_tx, mut reply_rx) = mpsc::channel(8);
+        let (request_tx, request_rx) = mpsc::channel(8);
+        let rw = ServerReaderWriter::new(reply_tx, request_rx, 0);
+        let method = MethodInfo {
+            service_name: "Echo",
+            method_name: "echo",
+            method_id: 0,
+            request_id: 0,
+        };
+        let ctx = CallContext::new(
+            method,
+            None,
+            Metadata::default(),
+            Extensions::new(),
+            CancellationToken::new(),
+        );
+
+        let flag = RequestFlag::default()
+            .set(RequestFlagBit::FIRST)
+            .set(RequestFlagBit::EOS);
+        let header = RequestHeader {
+            request_id: 0,
+            flag,
+            method_id: 0,
+            body_len: body.len() as u32,
+        };
+        let body = Bytes::from_static(body);
+        request_tx
+            .send(RequestFrame { header, body })
+            .await
+            .unwrap();
+
+        service.call_method(0, ctx, rw).await.unwrap();
+        reply_rx.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn calls_share_concurrency_limit() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let echo = {
+            let (active, peak) = (active.clone(), peak.clone());
+            service_fn(move |request: TowerRequest| {
+                let (active, peak) = (active.clone(), peak.clone());
+                async move {
+                    let n = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(n, Ordering::SeqCst);
+                    time::sleep(Duration::from_millis(20)).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, BoxError>((0, request.body))
+                }
+            })
+        };
+        let stack = ServiceBuilder::new()
+            .concurrency_limit(1)
+            .timeout(Duration::from_secs(5))
+            .service(echo);
+        let service = TowerService::new("Echo", &["echo"], stack);
+
+        let (a, b, c) = futures::join!(
+            call(&service, b"a"),
+            call(&service, b"b"),
+            call(&service, b"c")
+        );
+        for (reply, body) in [(a, "a"), (b, "b"), (c, "c")] {
+            assert_eq!(reply.header.status_code, 0);
+            assert_eq!(reply.body, body);
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+    }
+}